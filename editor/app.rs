@@ -15,10 +15,11 @@
 
 use std::fs::{self, File};
 use std::io;
-use std::io::{BufReader, Error, ErrorKind, Write};
+use std::io::{Error, ErrorKind};
 use eframe::{egui, epi};
 use eframe::egui::{Separator, Ui, WidgetText};
 
+use crate::config::{self, ConfigFile, Encryption, FilterType, Preservation};
 use crate::TaskConfig;
 
 enum TaskButtons {
@@ -47,7 +48,10 @@ pub struct ConfigEditor {
     editing: TaskConfig,
 
     #[cfg_attr(feature = "persistence", serde(skip))]
-    tasks: Vec<TaskConfig>
+    tasks: Vec<TaskConfig>,
+
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    files: Vec<ConfigFile>
 }
 
 macro_rules! labeled_field {
@@ -110,27 +114,27 @@ impl ConfigEditor {
                                               e)));
             }
         }
-        let mut file = File::create(&self.filename)?;
-        for task in &self.tasks {
-            file.write(task.to_string().as_ref())?;
+        if self.files.is_empty() {
+            return ConfigFile::new(&self.filename).write(&self.filename, self.tasks.iter());
+        }
+        // Tasks whose source isn't one of the loaded files go in the main file
+        for (i, file) in self.files.iter().enumerate() {
+            let tasks = self.tasks.iter().filter(|task| {
+                task.source == file.path
+                    || (i == 0 && !self.files.iter().any(|f| f.path == task.source))
+            });
+            file.write(if i == 0 { &self.filename } else { &file.path }, tasks)?;
         }
         Ok(())
     }
 
     fn load_from_disk(&mut self) -> io::Result<()> {
-        let mut new_tasks = Vec::new();
-        let file = File::open(&self.filename)?;
-        let mut reader = BufReader::new(file);
-        loop {
-            match TaskConfig::from_reader(&mut reader) {
-                Ok(task) => new_tasks.push(task),
-                Err(err) => match err.as_str() {
-                    "EOF" => break,
-                    err => return Err(Error::new(ErrorKind::Other, err))
-                }
-            }
-        }
-        self.tasks = new_tasks;
+        // Open the main file first so that a missing file is reported as such
+        File::open(&self.filename)?;
+        let (tasks, files) = config::load_config(&self.filename)
+            .map_err(Error::other)?;
+        self.tasks = tasks;
+        self.files = files;
         Ok(())
     }
 }
@@ -146,9 +150,84 @@ fn show_task(ui: &mut Ui, cfg: &TaskConfig) -> Option<TaskButtons> {
         ui.label("Always asks for confirmation");
     }
 
+    if !cfg.source.is_empty() {
+        ui.label(format!("File: {}", cfg.source));
+    }
+
+    if !cfg.schedule.is_empty() {
+        ui.label(format!("Schedule: {}", cfg.schedule));
+    }
+
+    if !cfg.after.is_empty() {
+        ui.label(format!("Runs after: {}", cfg.after.join(", ")));
+    }
+
     ui.label(format!("Source: {}", cfg.src));
     ui.label(format!("Destination: {}", cfg.dst));
 
+    if !cfg.require_mount.is_empty() {
+        ui.label(format!("Requires mount point: {}", cfg.require_mount));
+    }
+    if !cfg.require_marker.is_empty() {
+        ui.label(format!("Requires marker file: {}", cfg.require_marker));
+    }
+    if !cfg.require_uuid.is_empty() {
+        ui.label(format!("Requires filesystem UUID: {}", cfg.require_uuid));
+    }
+    if !cfg.min_free.is_empty() {
+        ui.label(format!("Keeps {} free on the destination", cfg.min_free));
+    }
+
+    if !cfg.bwlimit.is_empty() {
+        ui.label(format!("Limits bandwidth to {}", cfg.bwlimit));
+    }
+    if !cfg.work_bwlimit.is_empty() {
+        ui.label(format!("Limits bandwidth to {} during {}", cfg.work_bwlimit, cfg.work_hours));
+    }
+    if !cfg.nice.is_empty() || !cfg.ionice.is_empty() {
+        ui.label(format!(
+            "Runs with niceness {} and IO priority {}",
+            if cfg.nice.is_empty() { "0" } else { &cfg.nice },
+            if cfg.ionice.is_empty() { "best-effort" } else { &cfg.ionice }
+        ));
+    }
+
+    if !cfg.timeout.is_empty() {
+        ui.label(format!("Stops rsync after {}", cfg.timeout));
+    }
+    if !cfg.io_timeout.is_empty() {
+        ui.label(format!("Stops rsync after {} without transfers", cfg.io_timeout));
+    }
+    if !cfg.retries.is_empty() {
+        ui.label(format!("Retries up to {} times after transient failures", cfg.retries));
+    }
+
+    match cfg.encryption {
+        Encryption::None => {}
+        Encryption::Passphrase => {
+            ui.label("Encrypts snapshots with a passphrase");
+        }
+        Encryption::KeyFile => {
+            ui.label(format!("Encrypts snapshots with key file {}", cfg.key_file));
+        }
+    }
+
+    ui.label(match cfg.preservation {
+        Preservation::Basic => "Preserves modification times",
+        Preservation::Archive => "Preserves permissions, owners and special files",
+        Preservation::Full => "Preserves all metadata including ACLs and extended attributes",
+    });
+
+    if cfg.mirror {
+        ui.label("Mirrors source (deletes removed files)");
+        if !cfg.max_delete.is_empty() {
+            ui.label(format!("Deletes at most {} files", cfg.max_delete));
+        }
+        if !cfg.delete_threshold.is_empty() {
+            ui.label(format!("Confirms deleting over {}% of files", cfg.delete_threshold));
+        }
+    }
+
     if !cfg.is_update {
         ui.label(format!("Backups: {}", cfg.backup_path));
         if cfg.compare_paths {
@@ -160,6 +239,22 @@ fn show_task(ui: &mut Ui, cfg: &TaskConfig) -> Option<TaskButtons> {
         ui.label("Ignores all unincluded files");
     }
 
+    match cfg.include_hidden {
+        Some(true) => ui.label("Includes hidden files"),
+        Some(false) => ui.label("Excludes hidden files"),
+        None => ui.label("Hidden files use the global default"),
+    };
+
+    if !cfg.filters.is_empty() {
+        ui.label("Patterns:");
+        for (filter, pattern) in &cfg.filters {
+            ui.label(match filter {
+                FilterType::Include => format!("+ {}", pattern),
+                FilterType::Exclude => format!("- {}", pattern),
+            });
+        }
+    }
+
     ui.label("Links:");
     for path in &cfg.link_dest {
         ui.label(format!("- {}", &path));
@@ -170,6 +265,10 @@ fn show_task(ui: &mut Ui, cfg: &TaskConfig) -> Option<TaskButtons> {
         ui.label(format!("- {}", &path));
     }
 
+    if !cfg.rsync_args.is_empty() {
+        ui.label(format!("Extra rsync arguments: {}", cfg.rsync_args.join(" ")));
+    }
+
     if !cfg.exclude_from.is_empty() {
         ui.label(format!("Exclude patterns: {}", cfg.exclude_from));
     }
@@ -191,7 +290,7 @@ fn show_task(ui: &mut Ui, cfg: &TaskConfig) -> Option<TaskButtons> {
     ret
 }
 
-fn path_list_builder(ui: &mut Ui, label: impl Into<WidgetText>, paths: &mut Vec<String>) {
+fn path_list_builder(ui: &mut Ui, label: impl Into<WidgetText>, paths: &mut Vec<String>, add_label: &str) {
     let mut to_remove = None;
     ui.label(label);
     for (idx, path) in paths.iter_mut().enumerate() {
@@ -205,11 +304,32 @@ fn path_list_builder(ui: &mut Ui, label: impl Into<WidgetText>, paths: &mut Vec<
     if let Some(idx) = to_remove {
         paths.remove(idx);
     }
-    if ui.button("Add path").clicked() {
+    if ui.button(add_label).clicked() {
         paths.push(String::new());
     }
 }
 
+fn filter_list_builder(ui: &mut Ui, label: impl Into<WidgetText>, filters: &mut Vec<(FilterType, String)>) {
+    let mut to_remove = None;
+    ui.label(label);
+    for (idx, (filter, pattern)) in filters.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ui.radio_value(filter, FilterType::Include, "Include");
+            ui.radio_value(filter, FilterType::Exclude, "Exclude");
+            ui.text_edit_singleline(pattern);
+            if ui.button("Remove").clicked() {
+                to_remove = Some(idx);
+            }
+        });
+    }
+    if let Some(idx) = to_remove {
+        filters.remove(idx);
+    }
+    if ui.button("Add pattern").clicked() {
+        filters.push((FilterType::Exclude, String::new()));
+    }
+}
+
 fn task_editor(ui: &mut Ui, cfg: &mut TaskConfig) -> bool {
     ui.horizontal(|ui| {
         ui.radio_value(&mut cfg.is_update, true, "Update task");
@@ -220,16 +340,70 @@ fn task_editor(ui: &mut Ui, cfg: &mut TaskConfig) -> bool {
 
     ui.checkbox(&mut cfg.always_confirm, "Always ask for confirmation");
 
+    labeled_field!(ui, "Config file:", &mut cfg.source);
+
+    labeled_field!(ui, "Schedule (cron or interval):", &mut cfg.schedule);
+
+    path_list_builder(ui, "Run after tasks", &mut cfg.after, "Add task ID");
+
     labeled_field!(ui, "Source path:", &mut cfg.src);
     labeled_field!(ui, "Destination path:", &mut cfg.dst);
+    labeled_field!(ui, "Required mount point:", &mut cfg.require_mount);
+    labeled_field!(ui, "Required marker file (relative to destination):", &mut cfg.require_marker);
+    labeled_field!(ui, "Required filesystem UUID:", &mut cfg.require_uuid);
+    labeled_field!(ui, "Minimum free space (e.g. 20G):", &mut cfg.min_free);
+
+    labeled_field!(ui, "Bandwidth limit (e.g. 2M):", &mut cfg.bwlimit);
+    labeled_field!(ui, "Working hours (hours and weekdays, e.g. 9-17 1-5):", &mut cfg.work_hours);
+    labeled_field!(ui, "Bandwidth limit during working hours:", &mut cfg.work_bwlimit);
+    labeled_field!(ui, "Niceness (-20 to 19):", &mut cfg.nice);
+    labeled_field!(ui, "IO priority (e.g. idle or best-effort:7):", &mut cfg.ionice);
+    labeled_field!(ui, "Timeout (e.g. 6h):", &mut cfg.timeout);
+    labeled_field!(ui, "Timeout without transfers (e.g. 5m):", &mut cfg.io_timeout);
+    labeled_field!(ui, "Retries:", &mut cfg.retries);
+    labeled_field!(ui, "Delay before the first retry (default 30s):", &mut cfg.retry_delay);
+
+    ui.horizontal(|ui| {
+        ui.label("Encryption (backup tasks only):");
+        ui.radio_value(&mut cfg.encryption, Encryption::None, "None");
+        ui.radio_value(&mut cfg.encryption, Encryption::Passphrase, "Passphrase");
+        ui.radio_value(&mut cfg.encryption, Encryption::KeyFile, "Key file");
+    });
+    if cfg.encryption == Encryption::KeyFile {
+        labeled_field!(ui, "Key file:", &mut cfg.key_file);
+    }
+
+    ui.horizontal(|ui| {
+        ui.label("Preserve:");
+        ui.radio_value(&mut cfg.preservation, Preservation::Basic, "Basic");
+        ui.radio_value(&mut cfg.preservation, Preservation::Archive, "Archive");
+        ui.radio_value(&mut cfg.preservation, Preservation::Full, "Full");
+    });
+
+    ui.checkbox(&mut cfg.mirror, "Mirror source (update tasks only)");
+    if cfg.mirror {
+        labeled_field!(ui, "Maximum deletions:", &mut cfg.max_delete);
+        labeled_field!(ui, "Confirmation threshold (%):", &mut cfg.delete_threshold);
+    }
 
     labeled_field!(ui, "Backup path:", &mut cfg.backup_path);
     ui.checkbox(&mut cfg.compare_paths, "Compare with old backups");
 
     ui.checkbox(&mut cfg.exclude_others, "Exclude all unincluded files");
 
-    path_list_builder(ui, "Linked destinations", &mut cfg.link_dest);
-    path_list_builder(ui, "Compared destinations", &mut cfg.compare_dest);
+    ui.horizontal(|ui| {
+        ui.label("Hidden files:");
+        ui.radio_value(&mut cfg.include_hidden, None, "Default");
+        ui.radio_value(&mut cfg.include_hidden, Some(true), "Include");
+        ui.radio_value(&mut cfg.include_hidden, Some(false), "Exclude");
+    });
+
+    path_list_builder(ui, "Linked destinations", &mut cfg.link_dest, "Add path");
+    path_list_builder(ui, "Compared destinations", &mut cfg.compare_dest, "Add path");
+
+    filter_list_builder(ui, "Patterns (first match applies)", &mut cfg.filters);
+
+    path_list_builder(ui, "Extra rsync arguments (--option=value)", &mut cfg.rsync_args, "Add argument");
 
     labeled_editor_field!(ui, "Include from:", &mut cfg.include_from, cfg.editing_include);
     labeled_editor_field!(ui, "Exclude from:", &mut cfg.exclude_from, cfg.editing_exclude);
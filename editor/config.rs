@@ -12,8 +12,43 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use std::fmt::{Display, Formatter};
-use std::io::BufRead;
+use std::fs;
+use std::path::Path;
+
+use crate::format::{self, Entry, Format, Item};
+use crate::include::{self, Found};
+use crate::rsync_args::check_rsync_arg;
+
+/// A configuration file along with the `INCLUDE=` directives and settings it
+/// contains. Each directive is stored with the number of the file's own tasks
+/// that precede it so that the file can be written back in the same order.
+/// Settings are written at the top.
+pub struct ConfigFile {
+    pub path: String,
+    pub format: Format,
+    pub includes: Vec<(usize, String)>,
+    pub settings: Vec<(String, String)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Preservation {
+    Basic,
+    Archive,
+    Full,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encryption {
+    None,
+    Passphrase,
+    KeyFile,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterType {
+    Include,
+    Exclude,
+}
 
 pub struct TaskConfig {
     pub id: String,
@@ -29,55 +64,57 @@ pub struct TaskConfig {
     pub include_from: String,
     pub files_from: String,
     pub exclude_others: bool,
+    pub preservation: Preservation,
+    pub mirror: bool,
+    pub max_delete: String,
+    pub delete_threshold: String,
+    pub include_hidden: Option<bool>,
+    pub filters: Vec<(FilterType, String)>,
+    pub rsync_args: Vec<String>,
+    pub schedule: String,
+    pub after: Vec<String>,
+    pub require_mount: String,
+    pub require_marker: String,
+    pub require_uuid: String,
+    pub min_free: String,
+    pub encryption: Encryption,
+    pub key_file: String,
+    pub bwlimit: String,
+    pub work_bwlimit: String,
+    pub work_hours: String,
+    pub nice: String,
+    pub ionice: String,
+    pub timeout: String,
+    pub io_timeout: String,
+    pub retries: String,
+    pub retry_delay: String,
+
+    /// Configuration file the task was loaded from and will be saved to.
+    /// Tasks whose file wasn't loaded, like new tasks with an empty source,
+    /// are saved to the main configuration file.
+    pub source: String,
 
     pub editing_include: Option<String>,
     pub editing_exclude: Option<String>,
     pub editing_files: Option<String>,
 }
 
-macro_rules! write_if_nonempty {
-    ($f:ident, $label:tt, $parameter:expr) => {
+macro_rules! push_if_nonempty {
+    ($items:ident, $label:tt, $parameter:expr) => {
         if !$parameter.is_empty() {
-            writeln!($f, "{}={}", $label, $parameter)?;
+            $items.push(Item::Parameter(String::from($label), $parameter.clone()));
         }
     };
 }
 
-macro_rules! write_if_set {
-    ($f:ident, $indicator:tt, $parameter:expr) => {
+macro_rules! push_if_set {
+    ($items:ident, $indicator:tt, $parameter:expr) => {
         if $parameter {
-            writeln!($f, $indicator)?;
+            $items.push(Item::Flag(String::from($indicator)));
         }
     };
 }
 
-impl Display for TaskConfig {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{}\nSRC={}\nDST={}",
-               match self.is_update {
-                   true => "[UPDATE]",
-                   false => "[BACKUP]"
-               },
-               self.src, self.dst
-        )?;
-        write_if_nonempty!(f, "ID", self.id);
-        write_if_nonempty!(f, "EXFR", self.exclude_from);
-        write_if_nonempty!(f, "INFR", self.include_from);
-        write_if_nonempty!(f, "FIFR", self.files_from);
-        write_if_nonempty!(f, "BPATH", self.backup_path);
-        for path in &self.compare_dest {
-            writeln!(f, "CDST={}", path)?;
-        }
-        for path in &self.link_dest {
-            writeln!(f, "LDST={}", path)?;
-        }
-        write_if_set!(f, "[EXCLUDE OTHERS]", self.exclude_others);
-        write_if_set!(f, "[CONFIRM]", self.always_confirm);
-        write_if_set!(f, "[COMPARE BPATH]", self.compare_paths);
-        writeln!(f, "[END]")
-    }
-}
-
 impl Default for TaskConfig {
     fn default() -> Self {
         TaskConfig {
@@ -94,6 +131,31 @@ impl Default for TaskConfig {
             include_from: String::new(),
             files_from: String::new(),
             exclude_others: false,
+            preservation: Preservation::Basic,
+            mirror: false,
+            max_delete: String::new(),
+            delete_threshold: String::new(),
+            include_hidden: None,
+            filters: Vec::new(),
+            rsync_args: Vec::new(),
+            schedule: String::new(),
+            after: Vec::new(),
+            require_mount: String::new(),
+            require_marker: String::new(),
+            require_uuid: String::new(),
+            min_free: String::new(),
+            encryption: Encryption::None,
+            key_file: String::new(),
+            bwlimit: String::new(),
+            work_bwlimit: String::new(),
+            work_hours: String::new(),
+            nice: String::new(),
+            ionice: String::new(),
+            timeout: String::new(),
+            io_timeout: String::new(),
+            retries: String::new(),
+            retry_delay: String::new(),
+            source: String::new(),
             editing_include: None,
             editing_exclude: None,
             editing_files: None,
@@ -102,82 +164,16 @@ impl Default for TaskConfig {
 }
 
 impl TaskConfig {
-    pub fn from_reader(reader: &mut impl BufRead) -> Result<Self, String> {
-        let mut task = TaskConfig::default();
-        let mut type_determined = false;
-        loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(len) => {
-                    if len == 0 {
-                        return Err(String::from("EOF"));
-                    }
-                }
-                Err(err) => {
-                    return Err(err.to_string());
-                }
-            }
-            if line.starts_with("#") || line.len() == 1 {
-                continue;
-            }
-            let line = line.trim();
-            if !type_determined {
-                match line {
-                    "[BACKUP]" => task.is_update = false,
-                    "[UPDATE]" => {}
-                    _ => {
-                        return Err(String::from(
-                            "Failed to parse configuration file. Could not find task.",
-                        ));
-                    }
-                };
-                type_determined = true;
-                continue;
-            }
-            if line == "[END]" {
-                break;
+    pub fn from_items(items: &[Item], is_update: bool) -> Result<Self, String> {
+        let mut task = TaskConfig {
+            is_update,
+            ..Default::default()
+        };
+        for item in items {
+            match item {
+                Item::Parameter(key, value) => task.set_parameter(key, value)?,
+                Item::Flag(flag) => task.set_flag(flag)?,
             }
-            if let Some(path) = line.strip_prefix("SRC=") {
-                task.src = path.to_string();
-            } else if let Some(path) = line.strip_prefix("DST=") {
-                task.dst = path.to_string();
-            } else if let Some(path) = line.strip_prefix("EXFR=") {
-                task.exclude_from = path.to_string();
-            } else if let Some(path) = line.strip_prefix("INFR=") {
-                task.include_from = path.to_string();
-            } else if let Some(path) = line.strip_prefix("FIFR=") {
-                task.files_from = path.to_string();
-            } else if let Some(path) = line.strip_prefix("BPATH=") {
-                if task.is_update {
-                    return Err(String::from(
-                        "Unexpected BPATH parameter in update task configuration."
-                    ));
-                } else {
-                    task.backup_path = path.to_string();
-                }
-            } else if let Some(path) = line.strip_prefix("CDST=") {
-                task.compare_dest.push(path.to_string());
-            } else if let Some(path) = line.strip_prefix("LDST=") {
-                task.link_dest.push(path.to_string());
-            } else if let Some(name) = line.strip_prefix("ID=") {
-                task.id = name.to_string();
-            } else {
-                match line {
-                    "[EXCLUDE OTHERS]" => task.exclude_others = true,
-                    "[CONFIRM]" => task.always_confirm = true,
-                    "[COMPARE BPATH]" => if task.is_update {
-                        return Err(String::from(
-                            "Unexpected [COMPARE BPATH] tag in update task configuration."
-                        ));
-                    } else {
-                        task.compare_paths = true;
-                    },
-                    _ => return Err(format!("Unexpected line '{}' in configuration.", line))
-                };
-            }
-        }
-        if !type_determined {
-            return Err(String::from("EOF"));
         }
         if task.compare_paths && task.backup_path.is_empty() {
             return Err(String::from(
@@ -187,24 +183,299 @@ impl TaskConfig {
         Ok(task)
     }
 
-    pub fn validate(&self) -> Result<(), &'static str> {
+    fn set_parameter(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.to_string();
+        match key {
+            "SRC" => self.src = value,
+            "DST" => self.dst = value,
+            "EXFR" => self.exclude_from = value,
+            "INFR" => self.include_from = value,
+            "FIFR" => self.files_from = value,
+            "EXCLUDE" => self.filters.push((FilterType::Exclude, value)),
+            "INCLUDE" => self.filters.push((FilterType::Include, value)),
+            "PRESERVE" => self.preservation = match value.as_str() {
+                "basic" => Preservation::Basic,
+                "archive" => Preservation::Archive,
+                "full" => Preservation::Full,
+                _ => return Err(format!(
+                    "Invalid PRESERVE value '{}'. Expected 'basic', 'archive' or 'full'.", value
+                ))
+            },
+            "MAX_DELETE" => self.max_delete = value,
+            "DELETE_THRESHOLD" => self.delete_threshold = value,
+            "RSYNC_ARGS" => self.rsync_args.push(value),
+            "SCHEDULE" => self.schedule = value,
+            "AFTER" => self.after.push(value),
+            "REQUIRE_MOUNT" => self.require_mount = value,
+            "REQUIRE_MARKER" => self.require_marker = value,
+            "REQUIRE_UUID" => self.require_uuid = value,
+            "MIN_FREE" => self.min_free = value,
+            "ENCRYPT" => self.encryption = match value.as_str() {
+                "passphrase" => Encryption::Passphrase,
+                "keyfile" => Encryption::KeyFile,
+                _ => return Err(format!(
+                    "Invalid ENCRYPT value '{}'. Expected 'passphrase' or 'keyfile'.", value
+                ))
+            },
+            "KEYFILE" => self.key_file = value,
+            "BWLIMIT" => self.bwlimit = value,
+            "WORK_BWLIMIT" => self.work_bwlimit = value,
+            "WORK_HOURS" => self.work_hours = value,
+            "NICE" => self.nice = value,
+            "IONICE" => self.ionice = value,
+            "TIMEOUT" => self.timeout = value,
+            "IO_TIMEOUT" => self.io_timeout = value,
+            "RETRIES" => self.retries = value,
+            "RETRY_DELAY" => self.retry_delay = value,
+            "HIDDEN" => self.include_hidden = match value.as_str() {
+                "include" => Some(true),
+                "exclude" => Some(false),
+                _ => return Err(format!(
+                    "Invalid HIDDEN value '{}'. Expected 'include' or 'exclude'.", value
+                ))
+            },
+            "BPATH" => if self.is_update {
+                return Err(String::from(
+                    "Unexpected BPATH parameter in update task configuration."
+                ));
+            } else {
+                self.backup_path = value;
+            },
+            "CDST" => self.compare_dest.push(value),
+            "LDST" => self.link_dest.push(value),
+            "ID" => self.id = value,
+            _ => return Err(format!("Unexpected line '{}={}' in configuration.", key, value))
+        };
+        Ok(())
+    }
+
+    fn set_flag(&mut self, flag: &str) -> Result<(), String> {
+        match flag {
+            "[EXCLUDE OTHERS]" => self.exclude_others = true,
+            "[CONFIRM]" => self.always_confirm = true,
+            "[MIRROR]" => if self.is_update {
+                self.mirror = true;
+            } else {
+                return Err(String::from(
+                    "Unexpected [MIRROR] tag in backup task configuration."
+                ));
+            },
+            "[COMPARE BPATH]" => if self.is_update {
+                return Err(String::from(
+                    "Unexpected [COMPARE BPATH] tag in update task configuration."
+                ));
+            } else {
+                self.compare_paths = true;
+            },
+            _ => return Err(format!("Unexpected line '{}' in configuration.", flag))
+        };
+        Ok(())
+    }
+
+    /// Converts the task into a configuration entry for writing to disk
+    pub fn to_entry(&self) -> Entry {
+        let mut items = vec![
+            Item::Parameter(String::from("SRC"), self.src.clone()),
+            Item::Parameter(String::from("DST"), self.dst.clone()),
+        ];
+        push_if_nonempty!(items, "ID", self.id);
+        push_if_nonempty!(items, "EXFR", self.exclude_from);
+        push_if_nonempty!(items, "INFR", self.include_from);
+        push_if_nonempty!(items, "FIFR", self.files_from);
+        push_if_nonempty!(items, "BPATH", self.backup_path);
+        push_if_nonempty!(items, "REQUIRE_MOUNT", self.require_mount);
+        push_if_nonempty!(items, "REQUIRE_MARKER", self.require_marker);
+        push_if_nonempty!(items, "REQUIRE_UUID", self.require_uuid);
+        push_if_nonempty!(items, "MIN_FREE", self.min_free);
+        let encryption = match self.encryption {
+            Encryption::None => None,
+            Encryption::Passphrase => Some("passphrase"),
+            Encryption::KeyFile => Some("keyfile"),
+        };
+        if let Some(value) = encryption {
+            items.push(Item::Parameter(String::from("ENCRYPT"), String::from(value)));
+        }
+        push_if_nonempty!(items, "KEYFILE", self.key_file);
+        push_if_nonempty!(items, "BWLIMIT", self.bwlimit);
+        push_if_nonempty!(items, "WORK_BWLIMIT", self.work_bwlimit);
+        push_if_nonempty!(items, "WORK_HOURS", self.work_hours);
+        push_if_nonempty!(items, "NICE", self.nice);
+        push_if_nonempty!(items, "IONICE", self.ionice);
+        push_if_nonempty!(items, "TIMEOUT", self.timeout);
+        push_if_nonempty!(items, "IO_TIMEOUT", self.io_timeout);
+        push_if_nonempty!(items, "RETRIES", self.retries);
+        push_if_nonempty!(items, "RETRY_DELAY", self.retry_delay);
+        push_if_nonempty!(items, "SCHEDULE", self.schedule);
+        for id in &self.after {
+            items.push(Item::Parameter(String::from("AFTER"), id.clone()));
+        }
+        match self.preservation {
+            Preservation::Basic => {}
+            Preservation::Archive => items.push(Item::Parameter(String::from("PRESERVE"), String::from("archive"))),
+            Preservation::Full => items.push(Item::Parameter(String::from("PRESERVE"), String::from("full"))),
+        }
+        push_if_nonempty!(items, "MAX_DELETE", self.max_delete);
+        push_if_nonempty!(items, "DELETE_THRESHOLD", self.delete_threshold);
+        for path in &self.compare_dest {
+            items.push(Item::Parameter(String::from("CDST"), path.clone()));
+        }
+        for path in &self.link_dest {
+            items.push(Item::Parameter(String::from("LDST"), path.clone()));
+        }
+        for (filter, pattern) in &self.filters {
+            let key = match filter {
+                FilterType::Include => "INCLUDE",
+                FilterType::Exclude => "EXCLUDE",
+            };
+            items.push(Item::Parameter(String::from(key), pattern.clone()));
+        }
+        for arg in &self.rsync_args {
+            items.push(Item::Parameter(String::from("RSYNC_ARGS"), arg.clone()));
+        }
+        if let Some(include) = self.include_hidden {
+            let value = if include { "include" } else { "exclude" };
+            items.push(Item::Parameter(String::from("HIDDEN"), String::from(value)));
+        }
+        push_if_set!(items, "[EXCLUDE OTHERS]", self.exclude_others);
+        push_if_set!(items, "[CONFIRM]", self.always_confirm);
+        push_if_set!(items, "[COMPARE BPATH]", self.compare_paths);
+        push_if_set!(items, "[MIRROR]", self.mirror);
+        Entry::Task { is_update: self.is_update, items }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for arg in &self.rsync_args {
+            check_rsync_arg(arg)?;
+        }
         if self.src.is_empty() {
-            return Err("No source path specified");
+            return Err(String::from("No source path specified"));
         }
         if self.dst.is_empty() {
-            return Err("No destination path specified");
+            return Err(String::from("No destination path specified"));
+        }
+        if !self.min_free.is_empty()
+            && self.min_free.trim_end_matches(|c| "KMGTkmgt".contains(c)).parse::<u64>().is_err() {
+            return Err(String::from("Minimum free space must be a size such as 500M or 20G"));
+        }
+        if !self.max_delete.is_empty() && self.max_delete.parse::<u64>().is_err() {
+            return Err(String::from("Maximum deletions must be a number"));
+        }
+        if !self.delete_threshold.is_empty()
+            && !self.delete_threshold.parse::<f64>().is_ok_and(|t| (0.0..=100.0).contains(&t)) {
+            return Err(String::from("Deletion threshold must be a percentage"));
+        }
+        if !self.is_update && self.mirror {
+            return Err(String::from("Backup task can't be a mirror"));
         }
         if self.is_update {
             if self.compare_paths {
-                return Err("Update task can't compare with backups");
+                return Err(String::from("Update task can't compare with backups"));
             }
             if !self.backup_path.is_empty() {
-                return Err("Update task can't have backup path");
+                return Err(String::from("Update task can't have backup path"));
+            }
+            if self.encryption != Encryption::None {
+                return Err(String::from("Update task can't be encrypted"));
             }
         }
         if self.backup_path.is_empty() && self.compare_paths {
-            return Err("No backup path to compare to");
+            return Err(String::from("No backup path to compare to"));
+        }
+        if (self.encryption == Encryption::KeyFile) == self.key_file.is_empty() {
+            return Err(String::from(
+                "A key file must be given exactly when encrypting with a key file",
+            ));
+        }
+        if self.work_bwlimit.is_empty() != self.work_hours.is_empty() {
+            return Err(String::from(
+                "Working hours and their bandwidth limit must be given together",
+            ));
+        }
+        if self.encryption != Encryption::None
+            && (self.compare_paths || !self.link_dest.is_empty() || !self.compare_dest.is_empty()) {
+            return Err(String::from(
+                "Encrypted task can't link or compare with other destinations",
+            ));
         }
         Ok(())
     }
 }
+
+impl ConfigFile {
+    pub fn new(path: &str) -> Self {
+        ConfigFile {
+            path: path.to_string(),
+            format: Format::from_path(Path::new(path)),
+            includes: Vec::new(),
+            settings: Vec::new(),
+        }
+    }
+
+    /// Writes the file's settings, include directives and the given tasks,
+    /// which are expected to be the tasks whose source is this file, in the
+    /// format the file was loaded in
+    pub fn write<'a>(
+        &self,
+        path: &str,
+        tasks: impl Iterator<Item = &'a TaskConfig>,
+    ) -> std::io::Result<()> {
+        let mut entries: Vec<Entry> = self
+            .settings
+            .iter()
+            .map(|(key, value)| Entry::Setting(key.clone(), value.clone()))
+            .collect();
+        let mut includes = self.includes.iter().peekable();
+        for (i, task) in tasks.enumerate() {
+            while let Some((_, pattern)) = includes.next_if(|(idx, _)| *idx <= i) {
+                entries.push(Entry::Include(pattern.clone()));
+            }
+            entries.push(task.to_entry());
+        }
+        for (_, pattern) in includes {
+            entries.push(Entry::Include(pattern.clone()));
+        }
+        match format::write(self.format, &entries) {
+            Ok(contents) => fs::write(path, contents),
+            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// Loads the tasks in the given configuration file and any files it includes.
+/// Returns the tasks in order along with every file that was read, starting
+/// with the main file.
+pub fn load_config(path: &str) -> Result<(Vec<TaskConfig>, Vec<ConfigFile>), String> {
+    let mut tasks: Vec<TaskConfig> = Vec::new();
+    let mut files: Vec<ConfigFile> = Vec::new();
+    // Number of tasks already loaded when each file was started
+    let mut starts: Vec<usize> = Vec::new();
+    include::read(Path::new(path), &mut |file, found| {
+        let file = file.to_string_lossy().to_string();
+        match found {
+            Found::File => {
+                files.push(ConfigFile::new(&file));
+                starts.push(tasks.len());
+            }
+            Found::Entry(Entry::Include(pattern)) => {
+                let index = files.iter().rposition(|other| other.path == file).unwrap();
+                let task_count = tasks[starts[index]..]
+                    .iter()
+                    .filter(|task| task.source == file)
+                    .count();
+                files[index].includes.push((task_count, pattern));
+            }
+            Found::Entry(Entry::Setting(key, value)) => {
+                let index = files.iter().rposition(|other| other.path == file).unwrap();
+                files[index].settings.push((key, value));
+            }
+            Found::Entry(Entry::Task { is_update, items }) => {
+                let mut task = TaskConfig::from_items(&items, is_update)
+                    .map_err(|e| format!("{}: {}", file, e))?;
+                task.source = file;
+                tasks.push(task);
+            }
+        }
+        Ok(())
+    })?;
+    Ok((tasks, files))
+}
@@ -5,6 +5,12 @@
 
 mod app;
 mod config;
+#[path = "../src/format.rs"]
+mod format;
+#[path = "../src/include.rs"]
+mod include;
+#[path = "../src/rsync_args.rs"]
+mod rsync_args;
 
 use app::ConfigEditor;
 use config::TaskConfig;
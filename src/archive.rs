@@ -0,0 +1,284 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Export of snapshots to zstd-compressed tar archives and import of such
+// archives into the destination of a backup task. An archive holds a single
+// directory named after the snapshot and, if the snapshot has one, its
+// checksum manifest next to it. Encrypted archives are the same archives
+// encrypted with age.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chrono::NaiveDateTime;
+
+use crate::crypto::Key;
+use crate::manifest;
+use crate::task::SNAPSHOT_FORMAT;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+fn is_dir(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir())
+}
+
+/// Appends the contents of a directory of a snapshot to an archive under the
+/// given name. The directory is the merge of the given directories, newest
+/// first, with each entry taken from the newest one containing it. Entries
+/// below a directory only come from the layers where it is a directory and
+/// no newer layer has a file in its place. Returns the number of entries
+/// appended.
+fn append_merged<W: Write>(
+    builder: &mut tar::Builder<W>,
+    name: &Path,
+    dirs: &[PathBuf],
+) -> io::Result<usize> {
+    let mut entries = BTreeMap::new();
+    for dir in dirs {
+        let listing = fs::read_dir(dir).map_err(|why| {
+            io::Error::new(why.kind(), format!("Failed to read {}: {}", dir.display(), why))
+        })?;
+        for entry in listing {
+            let entry = entry?;
+            entries.entry(entry.file_name()).or_insert_with(|| entry.path());
+        }
+    }
+    let mut count = 0;
+    for (file_name, path) in entries {
+        let relative = name.join(&file_name);
+        builder.append_path_with_name(&path, &relative)?;
+        count += 1;
+        if is_dir(&path) {
+            let children: Vec<PathBuf> = dirs
+                .iter()
+                .map(|dir| dir.join(&file_name))
+                .take_while(|child| match fs::symlink_metadata(child) {
+                    Ok(metadata) => metadata.is_dir(),
+                    Err(_) => true,
+                })
+                .filter(|child| is_dir(child))
+                .collect();
+            count += append_merged(builder, &relative, &children)?;
+        }
+    }
+    Ok(count)
+}
+
+/// Writes a snapshot to a compressed tar archive. Returns the number of
+/// entries written.
+fn write_archive<W: Write>(name: &str, layers: &[PathBuf], output: W) -> io::Result<(W, usize)> {
+    let encoder = zstd::Encoder::new(output, COMPRESSION_LEVEL)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.mode(tar::HeaderMode::Complete);
+    builder.follow_symlinks(false);
+    builder.append_path_with_name(&layers[0], name)?;
+    let count = 1 + append_merged(&mut builder, Path::new(name), layers)?;
+    // A manifest only describes the snapshot it was written for, not a
+    // tree merged from several layers
+    let manifest = manifest::manifest_path(&layers[0]);
+    if layers.len() == 1 && manifest.exists() {
+        builder.append_path_with_name(&manifest, manifest.file_name().unwrap())?;
+    }
+    Ok((builder.into_inner()?.finish()?, count))
+}
+
+/// Creates a file by writing to a temporary file next to it and renaming it
+/// once it is complete
+fn create(output: &Path, write: impl FnOnce(File) -> io::Result<File>) -> Result<(), String> {
+    let mut temporary = output.as_os_str().to_os_string();
+    temporary.push(".partial");
+    let temporary = PathBuf::from(temporary);
+    let file = File::create(&temporary)
+        .map_err(|why| format!("Failed to create {}: {}", temporary.display(), why))?;
+    let written = write(file)
+        .and_then(|file| file.sync_all())
+        .and_then(|_| fs::rename(&temporary, output));
+    if let Err(why) = written {
+        let _ = fs::remove_file(&temporary);
+        return Err(format!("Failed to write {}: {}", output.display(), why));
+    }
+    Ok(())
+}
+
+/// Writes a snapshot, given by its name and layers (newest first), to a
+/// compressed tar archive. Returns the number of entries written.
+pub fn export(name: &str, layers: &[PathBuf], output: &Path) -> Result<usize, String> {
+    let mut count = 0;
+    create(output, |file| {
+        let (file, written) = write_archive(name, layers, file)?;
+        count = written;
+        Ok(file)
+    })?;
+    Ok(count)
+}
+
+/// Writes a snapshot to an encrypted archive. The plaintext is only ever
+/// held in memory.
+pub fn export_encrypted(
+    name: &str,
+    layers: &[PathBuf],
+    output: &Path,
+    key: &Key,
+) -> Result<usize, String> {
+    let mut count = 0;
+    create(output, |file| {
+        let (writer, written) = write_archive(name, layers, key.encrypt(file)?)?;
+        count = written;
+        writer.finish()
+    })?;
+    Ok(count)
+}
+
+fn unpack<R: Read>(input: R, target: &Path) -> io::Result<()> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(input)?);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    // Only root can give files to other users
+    archive.set_preserve_ownerships(unsafe { libc::geteuid() } == 0);
+    archive.unpack(target)
+}
+
+/// Finds the snapshot directory in an unpacked archive
+fn snapshot_name(staging: &Path) -> Result<String, String> {
+    let snapshots: Vec<String> = fs::read_dir(staging)
+        .map_err(|why| format!("Failed to read {}: {}", staging.display(), why))?
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    match &snapshots[..] {
+        [name] if NaiveDateTime::parse_from_str(name, SNAPSHOT_FORMAT).is_ok() => Ok(name.clone()),
+        _ => Err(String::from("Archive doesn't contain a single snapshot.")),
+    }
+}
+
+/// Moves the contents of an unpacked archive into the destination,
+/// returning the name of the snapshot
+fn place(staging: &Path, destination: &Path) -> Result<String, String> {
+    let name = snapshot_name(staging)?;
+    let names: Vec<String> = fs::read_dir(staging)
+        .map_err(|why| format!("Failed to read {}: {}", staging.display(), why))?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    let snapshot = destination.join(&name);
+    let manifest = manifest::manifest_path(&snapshot);
+    let manifest_name = manifest.file_name().unwrap().to_string_lossy().to_string();
+    if let Some(other) = names.iter().find(|other| **other != name && **other != manifest_name) {
+        return Err(format!("Unexpected entry {} in archive.", other));
+    }
+    if snapshot.exists() {
+        return Err(format!("Snapshot {} already exists.", name));
+    }
+    fs::rename(staging.join(&name), &snapshot)
+        .map_err(|why| format!("Failed to move snapshot to {}: {}", snapshot.display(), why))?;
+    if names.contains(&manifest_name) {
+        fs::rename(staging.join(&manifest_name), &manifest)
+            .map_err(|why| format!("Failed to move manifest to {}: {}", manifest.display(), why))?;
+    }
+    Ok(name)
+}
+
+/// Unpacks an archive created by `export` into the destination of a backup
+/// task. The archive is unpacked into a staging directory first so that an
+/// interrupted import doesn't leave a partial snapshot behind. Returns the
+/// name of the imported snapshot.
+pub fn import(input: &Path, destination: &Path) -> Result<String, String> {
+    let file = File::open(input)
+        .map_err(|why| format!("Failed to open {}: {}", input.display(), why))?;
+    let staging = destination.join(format!(".rsbackup-import-{}", process::id()));
+    fs::create_dir_all(&staging)
+        .map_err(|why| format!("Failed to create {}: {}", staging.display(), why))?;
+    let result = match unpack(file, &staging) {
+        Ok(_) => place(&staging, destination),
+        Err(why) => Err(format!("Failed to unpack {}: {}", input.display(), why)),
+    };
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+/// Unpacks the snapshot in an encrypted archive into a directory, which must
+/// not exist or be empty. Returns the name of the snapshot.
+pub fn extract_encrypted(input: &Path, key: &Key, target: &Path) -> Result<String, String> {
+    if let Ok(mut entries) = fs::read_dir(target) {
+        if entries.next().is_some() {
+            return Err(format!("{} is not empty.", target.display()));
+        }
+    }
+    let file = File::open(input)
+        .map_err(|why| format!("Failed to open {}: {}", input.display(), why))?;
+    let staging = target.join(format!(".rsbackup-extract-{}", process::id()));
+    fs::create_dir_all(&staging)
+        .map_err(|why| format!("Failed to create {}: {}", staging.display(), why))?;
+    let result = key
+        .decrypt(file)
+        .and_then(|decrypted| unpack(decrypted, &staging))
+        .map_err(|why| format!("Failed to decrypt {}: {}", input.display(), why))
+        .and_then(|_| snapshot_name(&staging))
+        .and_then(|name| {
+            let snapshot = staging.join(&name);
+            let entries = fs::read_dir(&snapshot)
+                .map_err(|why| format!("Failed to read {}: {}", snapshot.display(), why))?;
+            for entry in entries.flatten() {
+                fs::rename(entry.path(), target.join(entry.file_name()))
+                    .map_err(|why| format!("Failed to move {}: {}", entry.path().display(), why))?;
+            }
+            Ok(name)
+        });
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{assert_merged, write, write_layers};
+
+    #[test]
+    fn exported_snapshots_can_be_imported() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        write_layers(&new, &old);
+        let archive = dir.path().join("snapshot.tar.zst");
+        let name = "2024-01-02--00_00";
+        assert_eq!(export(name, &[new, old], &archive), Ok(6));
+
+        let destination = dir.path().join("destination");
+        fs::create_dir(&destination).unwrap();
+        assert_eq!(import(&archive, &destination), Ok(String::from(name)));
+        assert_merged(&destination.join(name));
+        assert_eq!(fs::read_dir(&destination).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn encrypted_snapshots_can_be_extracted() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("snapshot");
+        write(&snapshot, "dir/file.txt", "contents");
+        let key_file = dir.path().join("key");
+        crate::crypto::generate_key_file(&key_file).unwrap();
+        let key = Key::from_file(&key_file).unwrap();
+        let archive = dir.path().join("snapshot.tar.zst.age");
+        let name = "2024-01-01--00_00";
+        assert_eq!(export_encrypted(name, &[snapshot], &archive, &key), Ok(3));
+
+        let target = dir.path().join("extracted");
+        assert_eq!(extract_encrypted(&archive, &key, &target), Ok(String::from(name)));
+        assert_eq!(fs::read_to_string(target.join("dir/file.txt")).unwrap(), "contents");
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 1);
+    }
+}
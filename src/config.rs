@@ -0,0 +1,328 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::vec::Vec;
+
+use crate::format::{self, Entry, Format};
+use crate::include::{self, Found};
+use crate::task::{self, Task};
+
+const CONFIG_ENV_VAR: &str = "RSBACKUP_CONFIG";
+const SYSTEM_CONFIG: &str = "/etc/rsbackup/backup.conf";
+
+/// Loads all the tasks in the given configuration file, following any
+/// `INCLUDE=` directives in the order in which they appear. Tasks are then
+/// reordered so that every task comes after the tasks named in its `AFTER=`
+/// keys. Settings outside of tasks apply to the tasks in every file.
+pub fn load_tasks(path: &Path, debug: bool) -> Result<Vec<Task>, String> {
+    let mut tasks = Vec::new();
+    let mut include_hidden = None;
+    include::read(path, &mut |file, found| {
+        match found {
+            Found::Entry(Entry::Task { is_update, items }) => {
+                let task = Task::from_items(&items, is_update, debug)
+                    .map_err(|err| format!("{}: {}", file.display(), err))?;
+                tasks.push(task);
+            }
+            Found::Entry(Entry::Setting(key, value)) => {
+                include_hidden = Some(
+                    parse_setting(&key, &value)
+                        .map_err(|err| format!("{}: {}", file.display(), err))?,
+                );
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    if let Some(include) = include_hidden {
+        for task in &mut tasks {
+            task.set_default_hidden(include);
+        }
+    }
+    sort_tasks(tasks)
+}
+
+/// Parses a setting given outside of any task. HIDDEN is the only one and
+/// sets the default for tasks that don't set it themselves; if it is given
+/// more than once, the last value is used.
+fn parse_setting(key: &str, value: &str) -> Result<bool, String> {
+    match key {
+        "HIDDEN" => task::parse_hidden(value),
+        _ => Err(format!("Unknown setting {}.", key)),
+    }
+}
+
+/// Finds a dependency cycle among the given tasks, none of which can run
+/// before the others, starting from the first one
+fn find_cycle(tasks: &[Task]) -> Vec<String> {
+    let mut chain: Vec<&str> = Vec::new();
+    let mut current = &tasks[0];
+    loop {
+        if let Some(start) = chain.iter().position(|id| *id == current.get_id()) {
+            chain.push(current.get_id());
+            return chain[start..].iter().map(|id| id.to_string()).collect();
+        }
+        chain.push(current.get_id());
+        // Every remaining task has a prerequisite that is also remaining
+        current = tasks
+            .iter()
+            .find(|task| current.get_prerequisites().iter().any(|id| id == task.get_id()))
+            .unwrap();
+    }
+}
+
+/// Orders tasks so that prerequisites run first, keeping the order of the
+/// configuration file where possible. Task IDs must be unique so that
+/// prerequisites are unambiguous; tasks without an ID are not checked.
+fn sort_tasks(tasks: Vec<Task>) -> Result<Vec<Task>, String> {
+    for (index, task) in tasks.iter().enumerate() {
+        if task.has_id() && tasks[..index].iter().any(|other| other.get_id() == task.get_id()) {
+            return Err(format!("Task ID {} is used more than once.", task.get_id()));
+        }
+    }
+    for task in &tasks {
+        for id in task.get_prerequisites() {
+            if !tasks.iter().any(|other| other.get_id() == id) {
+                return Err(format!(
+                    "Task {} must run after task {}, which doesn't exist.",
+                    task.get_id(),
+                    id
+                ));
+            }
+        }
+    }
+    let mut remaining = tasks;
+    let mut sorted: Vec<Task> = Vec::new();
+    while !remaining.is_empty() {
+        let ready = remaining.iter().position(|task| {
+            task.get_prerequisites()
+                .iter()
+                .all(|id| !remaining.iter().any(|other| other.get_id() == id))
+        });
+        match ready {
+            Some(index) => sorted.push(remaining.remove(index)),
+            None => {
+                return Err(format!(
+                    "Dependency cycle detected: {}",
+                    find_cycle(&remaining).join(" -> ")
+                ));
+            }
+        }
+    }
+    Ok(sorted)
+}
+
+/// Translates a configuration file between the line and TOML formats, which
+/// are determined by the file extensions. Included files are not converted,
+/// and comments are dropped since neither format keeps them as entries.
+/// Returns the number of comment lines that were dropped.
+pub fn convert(input: &Path, output: &Path) -> Result<usize, String> {
+    let contents = fs::read_to_string(input)
+        .map_err(|why| format!("Failed to read {}: {}", input.display(), why))?;
+    let entries = format::parse(Format::from_path(input), &contents)
+        .map_err(|err| format!("{}: {}", input.display(), err))?;
+    let converted = format::write(Format::from_path(output), &entries)?;
+    match fs::write(output, converted) {
+        Ok(_) => Ok(contents
+            .lines()
+            .filter(|line| line.trim_start().starts_with('#'))
+            .count()),
+        Err(why) => Err(format!("Failed to write {}: {}", output.display(), why)),
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Location of the configuration file in the user's XDG configuration
+/// directory, where the legacy file is moved by `rsbackup config migrate`
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path,
+        _ => home_dir()?.join(".config"),
+    };
+    Some(config_home.join("rsbackup").join("backup.conf"))
+}
+
+/// Location of the configuration file used by earlier versions
+pub fn legacy_config_path() -> Option<PathBuf> {
+    Some(home_dir()?.join(".arcutillib").join("backup.conf"))
+}
+
+/// Determines which configuration file to use. A file given on the command
+/// line takes precedence, followed by `$RSBACKUP_CONFIG`, the user's XDG
+/// configuration directory, the system-wide configuration and finally the
+/// legacy location. Returns the path along with a description of where it
+/// was found.
+pub fn find_config(explicit: Option<&Path>) -> Result<(PathBuf, &'static str), String> {
+    if let Some(path) = explicit {
+        return Ok((path.to_path_buf(), "given on the command line"));
+    }
+    if let Some(path) = env::var_os(CONFIG_ENV_VAR).filter(|path| !path.is_empty()) {
+        return Ok((PathBuf::from(path), "from $RSBACKUP_CONFIG"));
+    }
+    let candidates = [
+        (user_config_path(), "user configuration"),
+        (Some(PathBuf::from(SYSTEM_CONFIG)), "system-wide configuration"),
+        (legacy_config_path(), "legacy configuration"),
+    ];
+    let mut searched = Vec::new();
+    for (path, description) in candidates {
+        if let Some(path) = path {
+            if path.is_file() {
+                return Ok((path, description));
+            }
+            searched.push(path.display().to_string());
+        }
+    }
+    Err(format!(
+        "No configuration file found. Searched: {}",
+        searched.join(", ")
+    ))
+}
+
+/// Moves the legacy configuration file to the user's XDG configuration
+/// directory and returns the new location
+pub fn migrate_legacy_config() -> Result<PathBuf, String> {
+    let (legacy, target) = match (legacy_config_path(), user_config_path()) {
+        (Some(legacy), Some(target)) => (legacy, target),
+        _ => return Err(String::from("Could not determine home directory.")),
+    };
+    if !legacy.is_file() {
+        return Err(format!(
+            "No legacy configuration file found at {}",
+            legacy.display()
+        ));
+    }
+    if target.exists() {
+        return Err(format!(
+            "Configuration file {} already exists.",
+            target.display()
+        ));
+    }
+    if let Some(parent) = target.parent() {
+        if let Err(why) = fs::create_dir_all(parent) {
+            return Err(format!("Failed to create {}: {}", parent.display(), why));
+        }
+    }
+    // Renaming fails if the directories are on different filesystems
+    if fs::rename(&legacy, &target).is_err() {
+        if let Err(why) = fs::copy(&legacy, &target).and_then(|_| fs::remove_file(&legacy)) {
+            return Err(format!(
+                "Failed to move {} to {}: {}",
+                legacy.display(),
+                target.display(),
+                why
+            ));
+        }
+    }
+    Ok(target)
+}
+
+/// Lists the relative `INCLUDE=` directives of a configuration file, which
+/// no longer resolve to the same files once the file has been moved
+pub fn relative_includes(path: &Path) -> Vec<String> {
+    match format::read_file(path) {
+        Ok(entries) => entries
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Entry::Include(pattern) if Path::new(&pattern).is_relative() => Some(pattern),
+                _ => None,
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads a configuration of update tasks given as an ID, if any, and the
+    /// IDs of their prerequisites
+    fn load(tasks: &[(Option<&str>, &[&str])]) -> Result<Vec<String>, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let mut contents = String::new();
+        for (id, after) in tasks {
+            contents.push_str("[UPDATE]\nSRC=/src\nDST=/dst\n");
+            if let Some(id) = id {
+                contents.push_str(&format!("ID={}\n", id));
+            }
+            for prerequisite in after.iter() {
+                contents.push_str(&format!("AFTER={}\n", prerequisite));
+            }
+            contents.push_str("[END]\n");
+        }
+        let path = dir.path().join("backup.conf");
+        fs::write(&path, contents).unwrap();
+        // Debug mode doesn't require the paths to exist
+        let tasks = load_tasks(&path, true)?;
+        Ok(tasks.iter().map(|task| task.get_id().to_string()).collect())
+    }
+
+    #[test]
+    fn keeps_order_without_prerequisites() {
+        let order = load(&[(Some("a"), &[]), (Some("b"), &[]), (Some("c"), &[])]);
+        assert_eq!(order.unwrap(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn runs_prerequisites_first() {
+        let order = load(&[
+            (Some("c"), &["a"]),
+            (Some("a"), &[]),
+            (Some("b"), &["c"]),
+            (Some("d"), &[]),
+        ]);
+        assert_eq!(order.unwrap(), ["a", "c", "b", "d"]);
+        let order = load(&[(Some("a"), &["b", "c"]), (Some("b"), &["c"]), (Some("c"), &[])]);
+        assert_eq!(order.unwrap(), ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn rejects_unknown_prerequisites() {
+        let err = load(&[(Some("a"), &["missing"])]).unwrap_err();
+        assert_eq!(err, "Task a must run after task missing, which doesn't exist.");
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let err = load(&[(Some("x"), &[]), (Some("a"), &["b"]), (Some("b"), &["a"])]);
+        assert_eq!(err.unwrap_err(), "Dependency cycle detected: a -> b -> a");
+        let err = load(&[(Some("a"), &["a"])]);
+        assert_eq!(err.unwrap_err(), "Dependency cycle detected: a -> a");
+        // Only the cycle is reported, not the tasks waiting on it
+        let err = load(&[
+            (Some("d"), &["a"]),
+            (Some("a"), &["c"]),
+            (Some("b"), &["a"]),
+            (Some("c"), &["b"]),
+        ]);
+        assert_eq!(err.unwrap_err(), "Dependency cycle detected: a -> c -> b -> a");
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let err = load(&[(Some("a"), &[]), (Some("b"), &[]), (Some("a"), &[])]);
+        assert_eq!(err.unwrap_err(), "Task ID a is used more than once.");
+        assert!(load(&[(None, &[]), (None, &[])]).is_ok());
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Encryption of snapshots with age (https://age-encryption.org). Keys are
+// either an age identity stored in a key file or a passphrase, which is read
+// from the environment for unattended runs or asked for on the terminal.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::iter;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use age::secrecy::{ExposeSecret, SecretString};
+use age::stream::{StreamReader, StreamWriter};
+use age::{scrypt, x25519, Decryptor, Encryptor};
+use chrono::Local;
+
+/// Environment variable holding the passphrase of tasks with
+/// ENCRYPT=passphrase, for runs without a terminal
+pub const PASSPHRASE_VAR: &str = "RSBACKUP_PASSPHRASE";
+
+/// Extension of encrypted snapshots
+pub const EXTENSION: &str = ".tar.zst.age";
+
+const SECRET_KEY_PREFIX: &str = "AGE-SECRET-KEY-";
+
+pub enum Key {
+    Passphrase(SecretString),
+    Identity(x25519::Identity),
+}
+
+impl Key {
+    /// Reads an age identity from a key file, as created by `rsbackup keygen`
+    /// or `age-keygen`
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|why| format!("Failed to read key file {}: {}", path.display(), why))?;
+        let line = contents
+            .lines()
+            .map(|line| line.trim())
+            .find(|line| line.starts_with(SECRET_KEY_PREFIX))
+            .ok_or_else(|| format!("No age secret key found in {}", path.display()))?;
+        x25519::Identity::from_str(line)
+            .map(Key::Identity)
+            .map_err(|why| format!("Invalid key in {}: {}", path.display(), why))
+    }
+
+    /// Gets a passphrase from the environment or, if allowed, by asking for
+    /// it. New passphrases are asked for twice.
+    pub fn passphrase(id: &str, interactive: bool, confirm: bool) -> Result<Self, String> {
+        if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+            return Ok(Key::Passphrase(SecretString::from(passphrase)));
+        }
+        if !interactive {
+            return Err(format!(
+                "Task {} needs a passphrase. Set {} to run it unattended.",
+                id, PASSPHRASE_VAR
+            ));
+        }
+        let read = |prompt: String| {
+            rpassword::prompt_password(prompt).map_err(|why| {
+                format!(
+                    "Failed to read passphrase ({}). Set {} to run task {} unattended.",
+                    why, PASSPHRASE_VAR, id
+                )
+            })
+        };
+        let passphrase = read(format!("Passphrase for task {}: ", id))?;
+        if passphrase.is_empty() {
+            return Err(String::from("Empty passphrase."));
+        }
+        if confirm && read(String::from("Repeat passphrase: "))? != passphrase {
+            return Err(String::from("Passphrases don't match."));
+        }
+        Ok(Key::Passphrase(SecretString::from(passphrase)))
+    }
+
+    /// Wraps a writer so that everything written to it is encrypted. The
+    /// returned writer must be finished to complete the encrypted file.
+    pub fn encrypt<W: Write>(&self, output: W) -> io::Result<StreamWriter<W>> {
+        let encryptor = match self {
+            Key::Passphrase(passphrase) => Encryptor::with_user_passphrase(passphrase.clone()),
+            Key::Identity(identity) => {
+                let recipient = identity.to_public();
+                Encryptor::with_recipients(iter::once(&recipient as _))
+                    .map_err(|why| io::Error::other(why.to_string()))?
+            }
+        };
+        encryptor.wrap_output(output)
+    }
+
+    /// Wraps a reader of an encrypted file so that the plaintext is read
+    /// from it. Fails if the file wasn't encrypted with this key.
+    pub fn decrypt<R: Read>(&self, input: R) -> io::Result<StreamReader<R>> {
+        let decryptor = Decryptor::new(input).map_err(|why| io::Error::other(why.to_string()))?;
+        let decrypted = match self {
+            Key::Passphrase(passphrase) => {
+                let identity = scrypt::Identity::new(passphrase.clone());
+                decryptor.decrypt(iter::once(&identity as _))
+            }
+            Key::Identity(identity) => decryptor.decrypt(iter::once(identity as _)),
+        };
+        decrypted.map_err(|why| io::Error::other(why.to_string()))
+    }
+}
+
+/// Generates a new age identity and writes it to a key file readable only by
+/// the current user. Returns the public key.
+pub fn generate_key_file(path: &Path) -> Result<String, String> {
+    let identity = x25519::Identity::generate();
+    let public = identity.to_public().to_string();
+    let contents = format!(
+        "# created: {}\n# public key: {}\n{}\n",
+        Local::now().to_rfc3339(),
+        public,
+        identity.to_string().expose_secret()
+    );
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|why| format!("Failed to write {}: {}", path.display(), why))?;
+    Ok(public)
+}
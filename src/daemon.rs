@@ -0,0 +1,237 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+use chrono::{DateTime, Local};
+
+use crate::schedule::{LastRuns, Schedule};
+use crate::task::{blocking_prerequisite, describe_retries, Outcomes, Task, TaskOutcome};
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn log(message: &str) {
+    println!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message);
+}
+
+/// Keeps only the tasks with a schedule, which must have unique IDs so that
+/// their last run times can be tracked
+fn scheduled_tasks(tasks: Vec<Task>) -> Result<Vec<Task>, String> {
+    let tasks: Vec<Task> = tasks
+        .into_iter()
+        .filter(|task| task.get_schedule().is_some())
+        .collect();
+    let mut ids = HashSet::new();
+    for task in &tasks {
+        if !ids.insert(task.get_id()) {
+            return Err(format!(
+                "Scheduled tasks must have unique IDs, but '{}' is used more than once.",
+                task.get_id()
+            ));
+        }
+    }
+    Ok(tasks)
+}
+
+/// Determines when a task should next run. Tasks that have never run start
+/// immediately if they run on an interval, or at the next time matching their
+/// cron expression after the daemon started.
+fn next_run(
+    schedule: &Schedule,
+    last_run: Option<DateTime<Local>>,
+    started: DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    match (last_run, schedule) {
+        (Some(last_run), _) => schedule.next_after(last_run),
+        (None, Schedule::Interval(_)) => Some(started),
+        (None, Schedule::Cron(_)) => schedule.next_after(started),
+    }
+}
+
+/// Runs the tasks that are due, in order. The tasks run together count as
+/// one run, so a task is skipped if one of its prerequisites ran and didn't
+/// complete, like with the run command.
+fn run_due<'a>(
+    tasks: &'a [Task],
+    last_runs: &mut LastRuns,
+    started: DateTime<Local>,
+    execute: &mut impl FnMut(&Task) -> TaskOutcome,
+) -> Outcomes<'a> {
+    let mut outcomes: Outcomes = Vec::new();
+    for task in tasks {
+        let schedule = task.get_schedule().unwrap();
+        let now = Local::now();
+        match next_run(schedule, last_runs.get(task.get_id()), started) {
+            Some(time) if time <= now => {}
+            _ => continue,
+        }
+        if let Err(err) = last_runs.record(task.get_id(), now) {
+            log(&format!("Failed to save last run times: {}", err));
+        }
+        if let Some(why) = blocking_prerequisite(task, &outcomes) {
+            log(&format!("Skipping task {}: {}", task.get_id(), why));
+            outcomes.push((task, TaskOutcome::Skipped(why)));
+            continue;
+        }
+        log(&format!("Running task {}", task.get_id()));
+        let outcome = execute(task);
+        match &outcome {
+            TaskOutcome::Completed(retries) => log(&format!(
+                "Task {} completed{}",
+                task.get_id(),
+                describe_retries(*retries)
+            )),
+            TaskOutcome::Skipped(why) => {
+                log(&format!("Task {} skipped: {}", task.get_id(), why))
+            }
+            TaskOutcome::Failed(why) => {
+                log(&format!("Task {} failed: {}", task.get_id(), why))
+            }
+        }
+        outcomes.push((task, outcome));
+    }
+    outcomes
+}
+
+/// Runs scheduled tasks of a configuration file as they become due until the
+/// process is terminated. Tasks run one at a time, so a task that is still
+/// running when it becomes due again runs once more after it finishes. Runs
+/// missed while the system was suspended or the daemon was stopped are caught
+/// up on with a single run. Retries also happen within a run, so a failing
+/// task with RETRIES delays the other tasks by up to an hour per retry.
+/// Sending SIGHUP reloads the configuration.
+pub fn run(
+    config: &Path,
+    load: impl Fn() -> Result<Vec<Task>, String>,
+    mut execute: impl FnMut(&Task) -> TaskOutcome,
+) -> bool {
+    let mut tasks = match load().and_then(scheduled_tasks) {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            println!("Failed to load configuration: {}", err);
+            return false;
+        }
+    };
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            request_reload as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    let mut last_runs = LastRuns::load(config);
+    let started = Local::now();
+    log(&format!("Started with {} scheduled tasks", tasks.len()));
+    loop {
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            match load().and_then(scheduled_tasks) {
+                Ok(new_tasks) => {
+                    tasks = new_tasks;
+                    log(&format!("Reloaded configuration with {} scheduled tasks", tasks.len()));
+                }
+                Err(err) => log(&format!(
+                    "Failed to reload configuration, keeping previous tasks: {}",
+                    err
+                )),
+            }
+        }
+        run_due(&tasks, &mut last_runs, started, &mut execute);
+        let wake = tasks
+            .iter()
+            .filter_map(|task| {
+                next_run(task.get_schedule().unwrap(), last_runs.get(task.get_id()), started)
+            })
+            .min();
+        // Sleep in short steps so that reload requests are handled promptly
+        // and the wall clock is checked again after a suspend
+        while !RELOAD_REQUESTED.load(Ordering::SeqCst)
+            && wake.is_none_or(|wake| wake > Local::now())
+        {
+            thread::sleep(time::Duration::from_secs(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Loads update tasks running every hour, given by their IDs and
+    /// prerequisites
+    fn load(dir: &Path, tasks: &[(&str, &str)]) -> Vec<Task> {
+        let contents: String = tasks
+            .iter()
+            .map(|(id, after)| {
+                format!(
+                    "[UPDATE]\nID={}\nSRC=/src\nDST=/dst/{}\nSCHEDULE=every 1h\n{}[END]\n",
+                    id, id, after
+                )
+            })
+            .collect();
+        let config = dir.join("backup.conf");
+        fs::write(&config, contents).unwrap();
+        crate::config::load_tasks(&config, true).unwrap()
+    }
+
+    #[test]
+    fn skips_tasks_whose_prerequisites_did_not_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let tasks = load(dir.path(), &[("a", ""), ("b", "AFTER=a\n"), ("c", "")]);
+        let mut last_runs = LastRuns::open(Some(dir.path().join("last-run")));
+        let mut executed = Vec::new();
+        let mut execute = |task: &Task| {
+            executed.push(task.get_id().to_string());
+            match task.get_id() {
+                "a" => TaskOutcome::Failed(String::from("Backup failed")),
+                _ => TaskOutcome::Completed(0),
+            }
+        };
+
+        let outcomes = run_due(&tasks, &mut last_runs, Local::now(), &mut execute);
+        assert_eq!(executed, ["a", "c"]);
+        let ids: Vec<&str> = outcomes.iter().map(|(task, _)| task.get_id()).collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+        match &outcomes[1].1 {
+            TaskOutcome::Skipped(why) => assert_eq!(why, "Prerequisite a failed"),
+            _ => panic!("Task b was not skipped"),
+        }
+        // Blocked tasks wait for their next scheduled run
+        assert!(last_runs.get("b").is_some());
+    }
+
+    #[test]
+    fn runs_tasks_only_when_due() {
+        let dir = tempfile::tempdir().unwrap();
+        let tasks = load(dir.path(), &[("a", ""), ("b", "AFTER=a\n")]);
+        let mut last_runs = LastRuns::open(Some(dir.path().join("last-run")));
+        last_runs.record("a", Local::now()).unwrap();
+        let mut executed = Vec::new();
+        let mut execute = |task: &Task| {
+            executed.push(task.get_id().to_string());
+            TaskOutcome::Completed(0)
+        };
+
+        // Prerequisites that aren't due don't block their dependents
+        run_due(&tasks, &mut last_runs, Local::now(), &mut execute);
+        assert_eq!(executed, ["b"]);
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Differences between two snapshots of a backup task, or between a snapshot
+// and the task's source. Files are compared by size and modification time;
+// files hard-linked between snapshots with --link-dest are unchanged.
+// Snapshots of tasks using [COMPARE BPATH] are compared as the merged view
+// of their layers, as materialize builds it.
+
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::manifest;
+use crate::task::format_size;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl Change {
+    fn name(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Modified => "modified",
+        }
+    }
+}
+
+pub struct Difference {
+    pub change: Change,
+    pub path: String,
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+impl Difference {
+    pub fn delta(&self) -> i64 {
+        self.new_size as i64 - self.old_size as i64
+    }
+}
+
+/// Formats a change in size with its sign
+pub fn format_delta(delta: i64) -> String {
+    match delta < 0 {
+        true => format!("-{}", format_size(delta.unsigned_abs())),
+        false => format!("+{}", format_size(delta as u64)),
+    }
+}
+
+/// Whether a relative path is inside the given prefix
+fn in_prefix(path: &str, prefix: Option<&str>) -> bool {
+    match prefix.map(|prefix| prefix.trim_matches('/')) {
+        None | Some("") => true,
+        Some(prefix) => Path::new(path).starts_with(prefix),
+    }
+}
+
+/// Whether a path in an older layer is hidden by the files already taken
+/// from newer layers: either the same path, a file at one of its parent
+/// directories, or files below it, which make it a directory
+fn is_shadowed(files: &BTreeMap<String, Metadata>, path: &str) -> bool {
+    let below = format!("{}/", path);
+    files.contains_key(path)
+        || Path::new(path)
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| files.contains_key(ancestor.to_string_lossy().as_ref()))
+        || files
+            .range(below.clone()..)
+            .next()
+            .is_some_and(|(other, _)| other.starts_with(&below))
+}
+
+/// Lists the files of a snapshot by relative path, optionally only under a
+/// path prefix. The snapshot is made of the given directories, newest first,
+/// as returned by Task::snapshot_layers; files in newer directories hide
+/// those in older ones. Directories that don't exist are treated as empty.
+pub fn tree(
+    layers: &[PathBuf],
+    prefix: Option<&str>,
+) -> Result<BTreeMap<String, Metadata>, String> {
+    let mut files = BTreeMap::new();
+    for (index, layer) in layers.iter().enumerate() {
+        if !layer.exists() {
+            continue;
+        }
+        let mut found = Vec::new();
+        for (relative, _, metadata) in manifest::walk(layer)? {
+            if in_prefix(&relative, prefix) && (index == 0 || !is_shadowed(&files, &relative)) {
+                found.push((relative, metadata));
+            }
+        }
+        files.extend(found);
+    }
+    Ok(files)
+}
+
+/// Compares two trees listed by `tree`. Returns the differences sorted by
+/// path.
+pub fn compare(
+    old: BTreeMap<String, Metadata>,
+    mut new: BTreeMap<String, Metadata>,
+) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    for (path, before) in old {
+        let difference = match new.remove(&path) {
+            None => Difference {
+                change: Change::Removed,
+                path,
+                old_size: before.len(),
+                new_size: 0,
+            },
+            Some(after) if before.dev() == after.dev() && before.ino() == after.ino() => continue,
+            Some(after) if before.len() == after.len() && before.mtime() == after.mtime() => {
+                continue
+            }
+            Some(after) => Difference {
+                change: Change::Modified,
+                path,
+                old_size: before.len(),
+                new_size: after.len(),
+            },
+        };
+        differences.push(difference);
+    }
+    for (path, after) in new {
+        differences.push(Difference {
+            change: Change::Added,
+            path,
+            old_size: 0,
+            new_size: after.len(),
+        });
+    }
+    differences.sort_by(|a, b| a.path.cmp(&b.path));
+    differences
+}
+
+fn json_string(value: &str) -> String {
+    let mut result = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Formats the differences between two trees as a JSON object
+pub fn to_json(from: &str, to: &str, differences: &[Difference]) -> String {
+    let changes: Vec<String> = differences
+        .iter()
+        .map(|difference| {
+            format!(
+                "    {{\"change\": \"{}\", \"path\": {}, \"old_size\": {}, \"new_size\": {}, \"delta\": {}}}",
+                difference.change.name(),
+                json_string(&difference.path),
+                difference.old_size,
+                difference.new_size,
+                difference.delta()
+            )
+        })
+        .collect();
+    let changes = match changes.is_empty() {
+        true => String::from("[]"),
+        false => format!("[\n{}\n  ]", changes.join(",\n")),
+    };
+    format!(
+        "{{\n  \"from\": {},\n  \"to\": {},\n  \"changes\": {}\n}}",
+        json_string(from),
+        json_string(to),
+        changes
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{write, write_layers};
+
+    #[test]
+    fn merges_snapshot_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        write_layers(&new, &old);
+        let files = tree(&[new, old, dir.path().join("missing")], None).unwrap();
+        let paths: Vec<&str> = files.keys().map(String::as_str).collect();
+        let expected = ["became_dir/child.txt", "became_file", "changed.txt", "same.txt"];
+        assert_eq!(paths, expected);
+        assert_eq!(files["changed.txt"].len(), 3);
+    }
+
+    #[test]
+    fn compares_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        write(&old, "removed.txt", "old");
+        write(&old, "modified.txt", "old");
+        write(&new, "modified.txt", "newer");
+        write(&new, "added.txt", "new");
+        let differences = compare(tree(&[old], None).unwrap(), tree(&[new], None).unwrap());
+        let changes: Vec<(&str, i64)> = differences
+            .iter()
+            .map(|difference| (difference.change.name(), difference.delta()))
+            .collect();
+        assert_eq!(changes, [("added", 3), ("modified", 2), ("removed", -3)]);
+    }
+}
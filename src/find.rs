@@ -0,0 +1,211 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Search for the versions of a file across the snapshots of a backup task.
+// Copies of a file that are hard-linked between snapshots or have the same
+// contents are grouped into a single version.
+
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::manifest::InodeCache;
+
+/// A version of a file and the snapshots containing it
+pub struct Version {
+    pub size: u64,
+    pub mtime: i64,
+    pub snapshots: Vec<String>,
+    /// Path of one of the copies, e.g. for restoring the file
+    pub path: PathBuf,
+    inodes: Vec<(u64, u64)>,
+    hash: Option<String>,
+}
+
+impl Version {
+    fn new(snapshot: &str, path: PathBuf, metadata: &Metadata) -> Self {
+        Version {
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+            snapshots: vec![snapshot.to_string()],
+            path,
+            inodes: vec![(metadata.dev(), metadata.ino())],
+            hash: None,
+        }
+    }
+
+    fn hash(&mut self, cache: &mut InodeCache) -> Result<&str, String> {
+        if self.hash.is_none() {
+            let metadata = fs::metadata(&self.path)
+                .map_err(|why| format!("Failed to read {}: {}", self.path.display(), why))?;
+            let hash = cache
+                .hash(&self.path, &metadata)
+                .map_err(|why| format!("Failed to read {}: {}", self.path.display(), why))?;
+            self.hash = Some(hash);
+        }
+        Ok(self.hash.as_deref().unwrap())
+    }
+}
+
+/// Finds the regular files in a snapshot matching a path or glob pattern,
+/// relative to the snapshot
+fn matches(snapshot: &Path, pattern: &str) -> Result<Vec<(String, PathBuf, Metadata)>, String> {
+    let pattern = pattern.trim_start_matches('/');
+    let paths: Vec<PathBuf> = match pattern.contains(['*', '?', '[']) {
+        true => {
+            let full = format!(
+                "{}/{}",
+                glob::Pattern::escape(&snapshot.to_string_lossy()),
+                pattern
+            );
+            glob::glob(&full)
+                .map_err(|why| format!("Invalid pattern {}: {}", pattern, why))?
+                .flatten()
+                .collect()
+        }
+        false => vec![snapshot.join(pattern)],
+    };
+    Ok(paths
+        .into_iter()
+        .filter_map(|path| {
+            let metadata = fs::symlink_metadata(&path).ok()?;
+            let relative = path.strip_prefix(snapshot).ok()?.to_string_lossy().to_string();
+            match metadata.is_file() {
+                true => Some((relative, path, metadata)),
+                false => None,
+            }
+        })
+        .collect())
+}
+
+/// Searches the given snapshots, from oldest to newest, for files matching a
+/// path or glob pattern and groups the copies of each file into versions
+pub fn find(
+    snapshots: &[(String, PathBuf)],
+    pattern: &str,
+    cache: &mut InodeCache,
+) -> Result<BTreeMap<String, Vec<Version>>, String> {
+    let mut files: BTreeMap<String, Vec<Version>> = BTreeMap::new();
+    for (name, snapshot) in snapshots {
+        for (relative, path, metadata) in matches(snapshot, pattern)? {
+            let versions = files.entry(relative).or_default();
+            let inode = (metadata.dev(), metadata.ino());
+            if let Some(version) = versions.iter_mut().find(|version| version.inodes.contains(&inode)) {
+                version.snapshots.push(name.clone());
+                continue;
+            }
+            let mut copy = Version::new(name, path, &metadata);
+            let mut same = None;
+            for (index, version) in versions.iter_mut().enumerate() {
+                if version.size == copy.size && version.hash(cache)? == copy.hash(cache)? {
+                    same = Some(index);
+                    break;
+                }
+            }
+            match same {
+                Some(index) => {
+                    versions[index].snapshots.push(name.clone());
+                    versions[index].inodes.push(inode);
+                }
+                None => versions.push(copy),
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::write;
+
+    /// Snapshot names and the snapshots of each version of a file
+    fn versions(files: &BTreeMap<String, Vec<Version>>) -> Vec<(&str, Vec<Vec<&str>>)> {
+        files
+            .iter()
+            .map(|(path, versions)| {
+                let snapshots = versions
+                    .iter()
+                    .map(|version| version.snapshots.iter().map(String::as_str).collect())
+                    .collect();
+                (path.as_str(), snapshots)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_hard_links_without_hashing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        write(&old, "file.txt", "contents");
+        fs::create_dir(&new).unwrap();
+        fs::hard_link(old.join("file.txt"), new.join("file.txt")).unwrap();
+        let snapshots = [(String::from("old"), old), (String::from("new"), new)];
+
+        let files = find(&snapshots, "file.txt", &mut InodeCache::default()).unwrap();
+        assert_eq!(versions(&files), [("file.txt", vec![vec!["old", "new"]])]);
+        assert!(files["file.txt"][0].hash.is_none());
+    }
+
+    #[test]
+    fn groups_copies_by_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = |name: &str, contents: &str| {
+            write(&dir.path().join(name), "file.txt", contents);
+            (String::from(name), dir.path().join(name))
+        };
+        let snapshots = [
+            snapshot("a", "first"),
+            snapshot("b", "first"),
+            snapshot("c", "other"),
+            snapshot("d", "longer"),
+            snapshot("e", "first"),
+        ];
+
+        let files = find(&snapshots, "file.txt", &mut InodeCache::default()).unwrap();
+        let expected = vec![vec!["a", "b", "e"], vec!["c"], vec!["d"]];
+        assert_eq!(versions(&files), [("file.txt", expected)]);
+        // Versions of a different size are told apart without hashing
+        assert!(files["file.txt"][2].hash.is_none());
+    }
+
+    #[test]
+    fn patterns_match_in_backup_path_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("dst/2024-01-02--00_00");
+        // Directories in BPATH needn't be named like snapshots
+        let older = dir.path().join("bpath/old [copy]");
+        write(&older, "docs/a.txt", "old");
+        write(&older, "docs/b.txt", "old");
+        write(&snapshot, "docs/a.txt", "new");
+        write(&snapshot, "docs/c.md", "new");
+        fs::create_dir(snapshot.join("docs/dir.txt")).unwrap();
+        let snapshots = [
+            (String::from("old [copy]"), older),
+            (String::from("2024-01-02--00_00"), snapshot),
+        ];
+
+        let files = find(&snapshots, "/docs/*.txt", &mut InodeCache::default()).unwrap();
+        assert_eq!(
+            versions(&files),
+            [
+                ("docs/a.txt", vec![vec!["old [copy]"], vec!["2024-01-02--00_00"]]),
+                ("docs/b.txt", vec![vec!["old [copy]"]]),
+            ]
+        );
+        let files = find(&snapshots, "docs/c.md", &mut InodeCache::default()).unwrap();
+        assert_eq!(versions(&files), [("docs/c.md", vec![vec!["2024-01-02--00_00"]])]);
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Syntax of the supported configuration file formats. This module only deals
+// with how entries are written down; what the parameters and flags mean is up
+// to the task parsers in the command line utility and the editor, which both
+// use this module.
+//
+// In the line format, tasks are delimited by [BACKUP] or [UPDATE] and [END],
+// parameters are written as KEY=value and flags as [FLAG NAME]. Lines are
+// trimmed, so values can't have leading or trailing whitespace. KEY=value
+// lines outside of tasks are settings for the whole configuration.
+//
+// In the TOML format, each entry is a [[task]] table with a `type` of
+// "backup", "update" or "include". Parameters are lowercase keys with string
+// values, or arrays of strings for parameters that are given more than once,
+// and flags are boolean keys with the brackets removed and spaces replaced by
+// underscores. Includes have a `path` key. For example, KEY=value becomes
+// key = "value" and [FLAG NAME] becomes flag_name = true. Parameters can also
+// be given as an array of tables, which keeps the order of different keys:
+// filter = [{ exclude = "*.tmp" }, { include = "*.c" }]. Settings are
+// lowercase top-level keys with string values.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use toml::value::{Table, Value};
+
+/// Parameters whose relative order matters. When a task uses more than one of
+/// them, they are written to TOML as a single array of tables under this key.
+const ORDERED_KEY: &str = "filter";
+const ORDERED_PARAMETERS: [&str; 2] = ["EXCLUDE", "INCLUDE"];
+
+pub enum Item {
+    Parameter(String, String),
+    Flag(String),
+}
+
+pub enum Entry {
+    Include(String),
+    Setting(String, String),
+    Task { is_update: bool, items: Vec<Item> },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Lines,
+    Toml,
+}
+
+impl Format {
+    /// Determines the format of a configuration file from its extension
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            _ => Format::Lines,
+        }
+    }
+}
+
+/// Reads the entries in a configuration file without following includes
+pub fn read_file(path: &Path) -> Result<Vec<Entry>, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => parse(Format::from_path(path), &contents)
+            .map_err(|err| format!("{}: {}", path.display(), err)),
+        Err(why) => Err(format!(
+            "Failed to read configuration file {}: {}",
+            path.display(),
+            why
+        )),
+    }
+}
+
+pub fn parse(format: Format, contents: &str) -> Result<Vec<Entry>, String> {
+    match format {
+        Format::Lines => parse_lines(contents),
+        Format::Toml => parse_toml(contents),
+    }
+}
+
+pub fn write(format: Format, entries: &[Entry]) -> Result<String, String> {
+    match format {
+        Format::Lines => write_lines(entries),
+        Format::Toml => write_toml(entries),
+    }
+}
+
+fn parse_lines(contents: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    let mut task: Option<(bool, Vec<Item>)> = None;
+    for (num, line) in contents.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let line = line.trim();
+        match task.as_mut() {
+            None => {
+                if let Some(path) = line.strip_prefix("INCLUDE=") {
+                    entries.push(Entry::Include(path.to_string()));
+                    continue;
+                }
+                if let Some((key, value)) = line.split_once('=') {
+                    entries.push(Entry::Setting(key.to_string(), value.to_string()));
+                    continue;
+                }
+                let is_update = match line {
+                    "[BACKUP]" => false,
+                    "[UPDATE]" => true,
+                    _ => {
+                        return Err(format!(
+                            "Line {}: Failed to parse configuration file. Could not find task.",
+                            num + 1
+                        ));
+                    }
+                };
+                task = Some((is_update, Vec::new()));
+            }
+            Some((_, items)) => {
+                if line == "[END]" {
+                    let (is_update, items) = task.take().unwrap();
+                    entries.push(Entry::Task { is_update, items });
+                } else if let Some((key, value)) = line.split_once('=') {
+                    items.push(Item::Parameter(key.to_string(), value.to_string()));
+                } else {
+                    items.push(Item::Flag(line.to_string()));
+                }
+            }
+        }
+    }
+    if task.is_some() {
+        return Err(String::from("Unexpected end of file. Missing [END] tag."));
+    }
+    Ok(entries)
+}
+
+fn write_lines(entries: &[Entry]) -> Result<String, String> {
+    let mut contents = String::new();
+    for entry in entries {
+        match entry {
+            Entry::Include(path) => {
+                check_line_value("INCLUDE", path)?;
+                contents.push_str(&format!("INCLUDE={}\n", path));
+            }
+            Entry::Setting(key, value) => {
+                check_line_value(key, value)?;
+                contents.push_str(&format!("{}={}\n", key, value));
+            }
+            Entry::Task { is_update, items } => {
+                contents.push_str(match is_update {
+                    true => "[UPDATE]\n",
+                    false => "[BACKUP]\n",
+                });
+                for item in items {
+                    match item {
+                        Item::Parameter(key, value) => {
+                            check_line_value(key, value)?;
+                            contents.push_str(&format!("{}={}\n", key, value));
+                        }
+                        Item::Flag(flag) => {
+                            contents.push_str(flag);
+                            contents.push('\n');
+                        }
+                    }
+                }
+                contents.push_str("[END]\n");
+            }
+        }
+    }
+    Ok(contents)
+}
+
+fn check_line_value(key: &str, value: &str) -> Result<(), String> {
+    if value.contains('\n') || value.trim() != value {
+        return Err(format!(
+            "Value '{}' for {} can't be represented in the line format.",
+            value, key
+        ));
+    }
+    Ok(())
+}
+
+fn flag_to_key(flag: &str) -> Result<String, String> {
+    match flag.strip_prefix('[').and_then(|flag| flag.strip_suffix(']')) {
+        Some(name) => Ok(name.to_lowercase().replace(' ', "_")),
+        None => Err(format!("Unexpected line '{}' in configuration.", flag)),
+    }
+}
+
+fn key_to_flag(key: &str) -> String {
+    format!("[{}]", key.to_uppercase().replace('_', " "))
+}
+
+fn value_to_string(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        _ => Err(format!("Unexpected value for '{}'.", key)),
+    }
+}
+
+fn parse_toml(contents: &str) -> Result<Vec<Entry>, String> {
+    let mut document: Table = match toml::from_str(contents) {
+        Ok(document) => document,
+        Err(why) => return Err(why.to_string()),
+    };
+    let tables = match document.remove("task") {
+        Some(Value::Array(tables)) => tables,
+        Some(_) => return Err(String::from("'task' must be an array of tables.")),
+        None => Vec::new(),
+    };
+    let mut entries = Vec::new();
+    for (key, value) in document {
+        match value {
+            Value::String(value) => entries.push(Entry::Setting(key.to_uppercase(), value)),
+            _ => return Err(format!("Setting '{}' must be a string.", key)),
+        }
+    }
+    for (num, table) in tables.into_iter().enumerate() {
+        let table = match table {
+            Value::Table(table) => table,
+            _ => return Err(format!("Task #{}: Expected a table.", num + 1)),
+        };
+        let is_update = match table.get("type").and_then(|kind| kind.as_str()) {
+            Some("update") => true,
+            Some("backup") => false,
+            Some("include") => {
+                let path = match table.get("path") {
+                    Some(Value::String(path)) => path.clone(),
+                    _ => return Err(format!("Task #{}: Include has no path.", num + 1)),
+                };
+                if let Some(key) = table.keys().find(|key| *key != "type" && *key != "path") {
+                    return Err(format!(
+                        "Task #{}: Unexpected key '{}' in include.",
+                        num + 1,
+                        key
+                    ));
+                }
+                entries.push(Entry::Include(path));
+                continue;
+            }
+            _ => {
+                return Err(format!(
+                    "Task #{}: 'type' must be \"backup\", \"update\" or \"include\".",
+                    num + 1
+                ));
+            }
+        };
+        let mut items = Vec::new();
+        for (key, value) in table.into_iter().filter(|(key, _)| key != "type") {
+            let name = key.to_uppercase();
+            match value {
+                Value::Boolean(set) => {
+                    if set {
+                        items.push(Item::Flag(key_to_flag(&key)));
+                    }
+                }
+                Value::Array(values) => {
+                    for value in &values {
+                        if let Value::Table(params) = value {
+                            for (key, value) in params {
+                                let value = value_to_string(key, value)
+                                    .map_err(|err| format!("Task #{}: {}", num + 1, err))?;
+                                items.push(Item::Parameter(key.to_uppercase(), value));
+                            }
+                            continue;
+                        }
+                        let value = value_to_string(&key, value)
+                            .map_err(|err| format!("Task #{}: {}", num + 1, err))?;
+                        items.push(Item::Parameter(name.clone(), value));
+                    }
+                }
+                value => {
+                    let value = value_to_string(&key, &value)
+                        .map_err(|err| format!("Task #{}: {}", num + 1, err))?;
+                    items.push(Item::Parameter(name, value));
+                }
+            }
+        }
+        entries.push(Entry::Task { is_update, items });
+    }
+    Ok(entries)
+}
+
+fn write_toml(entries: &[Entry]) -> Result<String, String> {
+    let mut document = Table::new();
+    let mut tables = Vec::new();
+    for entry in entries {
+        let mut table = Table::new();
+        match entry {
+            Entry::Setting(key, value) => {
+                let key_name = key.to_lowercase();
+                if key_name == "task" {
+                    return Err(format!("Setting {} has no TOML equivalent.", key));
+                }
+                document.insert(key_name, Value::from(value.as_str()));
+                continue;
+            }
+            Entry::Include(path) => {
+                table.insert(String::from("type"), Value::from("include"));
+                table.insert(String::from("path"), Value::from(path.as_str()));
+            }
+            Entry::Task { is_update, items } => {
+                let kind = match is_update {
+                    true => "update",
+                    false => "backup",
+                };
+                table.insert(String::from("type"), Value::from(kind));
+                // Parameters given more than once are collected into arrays
+                let mut counts = HashMap::new();
+                for item in items {
+                    if let Item::Parameter(key, _) = item {
+                        *counts.entry(key.as_str()).or_insert(0) += 1;
+                    }
+                }
+                let ordered = ORDERED_PARAMETERS
+                    .iter()
+                    .filter(|key| counts.contains_key(*key))
+                    .count()
+                    > 1;
+                for item in items {
+                    match item {
+                        Item::Parameter(key, value) => {
+                            let key_name = key.to_lowercase();
+                            if key_name == "type" || key_name == ORDERED_KEY {
+                                return Err(format!("Parameter {} has no TOML equivalent.", key));
+                            }
+                            if ordered && ORDERED_PARAMETERS.contains(&key.as_str()) {
+                                let mut param = Table::new();
+                                param.insert(key_name, Value::from(value.as_str()));
+                                let values = table
+                                    .entry(ORDERED_KEY)
+                                    .or_insert_with(|| Value::Array(Vec::new()));
+                                if let Value::Array(values) = values {
+                                    values.push(Value::Table(param));
+                                }
+                            } else if counts[key.as_str()] > 1 {
+                                let values = table
+                                    .entry(key_name)
+                                    .or_insert_with(|| Value::Array(Vec::new()));
+                                if let Value::Array(values) = values {
+                                    values.push(Value::from(value.as_str()));
+                                }
+                            } else {
+                                table.insert(key_name, Value::from(value.as_str()));
+                            }
+                        }
+                        Item::Flag(flag) => {
+                            table.insert(flag_to_key(flag)?, Value::Boolean(true));
+                        }
+                    }
+                }
+            }
+        }
+        tables.push(Value::Table(table));
+    }
+    document.insert(String::from("task"), Value::Array(tables));
+    toml::to_string(&document).map_err(|why| why.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A configuration using the parameters and flags of both task types
+    const CONFIG: &str = include_str!("../tests/fixtures/backup.conf");
+
+    #[test]
+    fn lines_round_trip_through_toml() {
+        let toml = write(Format::Toml, &parse(Format::Lines, CONFIG).unwrap()).unwrap();
+        let lines = write(Format::Lines, &parse(Format::Toml, &toml).unwrap()).unwrap();
+        // Filters are written after the other parameters of their task, since
+        // they share one TOML key, so they only keep their place once moved
+        let filters = "EXCLUDE=*.tmp\nINCLUDE=*.c\nEXCLUDE=*.o\n";
+        let mut grouped = CONFIG.to_string();
+        for filter in filters.lines() {
+            grouped = grouped.replacen(&format!("{}\n", filter), "", 1);
+        }
+        let grouped = grouped.replacen("[END]", &format!("{}[END]", filters), 1);
+        assert_ne!(grouped, CONFIG);
+        assert_eq!(lines, grouped);
+        let again = write(Format::Toml, &parse(Format::Lines, &lines).unwrap()).unwrap();
+        assert_eq!(again, toml);
+    }
+
+    #[test]
+    fn comments_are_not_entries() {
+        let contents = "# Backups\n\n[BACKUP]\n# Home\nID=home\n[END]\n";
+        let entries = parse(Format::Lines, contents).unwrap();
+        assert_eq!(write(Format::Lines, &entries).unwrap(), "[BACKUP]\nID=home\n[END]\n");
+    }
+}
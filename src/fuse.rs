@@ -0,0 +1,397 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Read-only FUSE filesystem exposing each snapshot of a backup task as a
+// directory. Every snapshot is shown as the merge of its layers (see
+// Task::snapshot_layers), with files taken from the newest layer that has
+// them, so snapshots of [COMPARE BPATH] tasks appear complete.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEntry, Request,
+};
+
+const TTL: Duration = Duration::from_secs(60);
+const ROOT: u64 = 1;
+
+/// A file or directory in the filesystem, given by the snapshot containing
+/// it and its path relative to the snapshot
+enum Node {
+    Root,
+    Entry { snapshot: usize, relative: PathBuf },
+}
+
+pub struct SnapshotFs {
+    /// Names of the snapshots and their layers, newest first
+    snapshots: Vec<(String, Vec<PathBuf>)>,
+    /// Nodes and the inode numbers of their parents by inode number minus one
+    nodes: Vec<(Node, u64)>,
+    inodes: HashMap<(u64, OsString), u64>,
+    mounted: SystemTime,
+}
+
+fn file_type(metadata: &Metadata) -> FileType {
+    let kind = metadata.file_type();
+    if kind.is_dir() {
+        FileType::Directory
+    } else if kind.is_symlink() {
+        FileType::Symlink
+    } else if kind.is_fifo() {
+        FileType::NamedPipe
+    } else if kind.is_socket() {
+        FileType::Socket
+    } else if kind.is_char_device() {
+        FileType::CharDevice
+    } else if kind.is_block_device() {
+        FileType::BlockDevice
+    } else {
+        FileType::RegularFile
+    }
+}
+
+/// Whether a layer has something other than a directory at a path or one of
+/// its parents, which hides that path in all older layers
+fn has_file_at(layer: &Path, relative: &Path) -> bool {
+    relative
+        .ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| {
+            fs::symlink_metadata(layer.join(ancestor)).is_ok_and(|metadata| !metadata.is_dir())
+        })
+}
+
+fn time(seconds: i64, nanoseconds: i64) -> SystemTime {
+    match seconds >= 0 {
+        true => UNIX_EPOCH + Duration::new(seconds as u64, nanoseconds as u32),
+        false => UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()),
+    }
+}
+
+fn attributes(ino: u64, metadata: &Metadata) -> FileAttr {
+    FileAttr {
+        ino,
+        size: metadata.len(),
+        blocks: metadata.blocks(),
+        atime: time(metadata.atime(), metadata.atime_nsec()),
+        mtime: time(metadata.mtime(), metadata.mtime_nsec()),
+        ctime: time(metadata.ctime(), metadata.ctime_nsec()),
+        crtime: time(metadata.mtime(), metadata.mtime_nsec()),
+        kind: file_type(metadata),
+        // Nothing can be written, so don't claim otherwise
+        perm: (metadata.mode() & 0o7555) as u16,
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        blksize: metadata.blksize() as u32,
+        flags: 0,
+    }
+}
+
+impl SnapshotFs {
+    pub fn new(snapshots: Vec<(String, Vec<PathBuf>)>) -> Self {
+        let mut fs = SnapshotFs {
+            snapshots: Vec::new(),
+            nodes: vec![(Node::Root, ROOT)],
+            inodes: HashMap::new(),
+            mounted: SystemTime::now(),
+        };
+        for (index, (name, _)) in snapshots.iter().enumerate() {
+            fs.inode(
+                ROOT,
+                OsStr::new(name),
+                Node::Entry {
+                    snapshot: index,
+                    relative: PathBuf::new(),
+                },
+            );
+        }
+        fs.snapshots = snapshots;
+        fs
+    }
+
+    /// Returns the inode number of a node, assigning one if it is new
+    fn inode(&mut self, parent: u64, name: &OsStr, node: Node) -> u64 {
+        let key = (parent, name.to_os_string());
+        if let Some(ino) = self.inodes.get(&key) {
+            return *ino;
+        }
+        self.nodes.push((node, parent));
+        let ino = self.nodes.len() as u64;
+        self.inodes.insert(key, ino);
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize).map(|(node, _)| node)
+    }
+
+    /// The inode number of the directory containing a node. The root is its
+    /// own parent.
+    fn parent(&self, ino: u64) -> u64 {
+        self.nodes[ino as usize - 1].1
+    }
+
+    /// Finds the file backing a path in a snapshot in the newest layer
+    /// containing it. Layers older than one with a file in place of one of
+    /// the path's parent directories don't count.
+    fn resolve(&self, snapshot: usize, relative: &Path) -> Option<(PathBuf, Metadata)> {
+        for layer in &self.snapshots[snapshot].1 {
+            if relative.parent().is_some_and(|parent| has_file_at(layer, parent)) {
+                return None;
+            }
+            let path = layer.join(relative);
+            if let Ok(metadata) = fs::symlink_metadata(&path) {
+                return Some((path, metadata));
+            }
+        }
+        None
+    }
+
+    /// Lists a directory in a snapshot by merging the directories of all its
+    /// layers, up to the first layer with a file in place of the directory or
+    /// one of its parents
+    fn list(&self, snapshot: usize, relative: &Path) -> BTreeMap<OsString, FileType> {
+        let mut entries = BTreeMap::new();
+        for layer in &self.snapshots[snapshot].1 {
+            if has_file_at(layer, relative) {
+                break;
+            }
+            for entry in fs::read_dir(layer.join(relative)).into_iter().flatten().flatten() {
+                if let Ok(metadata) = entry.metadata() {
+                    entries
+                        .entry(entry.file_name())
+                        .or_insert_with(|| file_type(&metadata));
+                }
+            }
+        }
+        entries
+    }
+
+    fn root_attributes(&self) -> FileAttr {
+        FileAttr {
+            ino: ROOT,
+            size: 0,
+            blocks: 0,
+            atime: self.mounted,
+            mtime: self.mounted,
+            ctime: self.mounted,
+            crtime: self.mounted,
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+
+    fn attributes(&self, ino: u64) -> Option<FileAttr> {
+        match self.node(ino)? {
+            Node::Root => Some(self.root_attributes()),
+            Node::Entry { snapshot, relative } => {
+                let (_, metadata) = self.resolve(*snapshot, relative)?;
+                Some(attributes(ino, &metadata))
+            }
+        }
+    }
+}
+
+impl Filesystem for SnapshotFs {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let node = match self.node(parent) {
+            Some(Node::Root) => {
+                let found = self
+                    .snapshots
+                    .iter()
+                    .position(|(snapshot, _)| name == OsStr::new(snapshot));
+                match found {
+                    Some(snapshot) => Node::Entry {
+                        snapshot,
+                        relative: PathBuf::new(),
+                    },
+                    None => return reply.error(libc::ENOENT),
+                }
+            }
+            Some(Node::Entry { snapshot, relative }) => Node::Entry {
+                snapshot: *snapshot,
+                relative: relative.join(name),
+            },
+            None => return reply.error(libc::ENOENT),
+        };
+        if let Node::Entry { snapshot, relative } = &node {
+            if self.resolve(*snapshot, relative).is_none() {
+                return reply.error(libc::ENOENT);
+            }
+        }
+        let ino = self.inode(parent, name, node);
+        match self.attributes(ino) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attributes(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let target = match self.node(ino) {
+            Some(Node::Entry { snapshot, relative }) => self
+                .resolve(*snapshot, relative)
+                .and_then(|(path, _)| fs::read_link(path).ok()),
+            _ => None,
+        };
+        match target {
+            Some(target) => reply.data(target.as_os_str().as_bytes()),
+            None => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let path = match self.node(ino) {
+            Some(Node::Entry { snapshot, relative }) => self.resolve(*snapshot, relative),
+            _ => None,
+        };
+        let path = match path {
+            Some((path, metadata)) if metadata.is_file() => path,
+            Some(_) => return reply.error(libc::EISDIR),
+            None => return reply.error(libc::ENOENT),
+        };
+        let mut buffer = vec![0; size as usize];
+        match File::open(path).and_then(|file| file.read_at(&mut buffer, offset as u64)) {
+            Ok(read) => reply.data(&buffer[..read]),
+            Err(why) => reply.error(why.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries: Vec<(OsString, FileType, Node)> = match self.node(ino) {
+            Some(Node::Root) => self
+                .snapshots
+                .iter()
+                .enumerate()
+                .map(|(snapshot, (name, _))| {
+                    let node = Node::Entry {
+                        snapshot,
+                        relative: PathBuf::new(),
+                    };
+                    (OsString::from(name), FileType::Directory, node)
+                })
+                .collect(),
+            Some(Node::Entry { snapshot, relative }) => self
+                .list(*snapshot, relative)
+                .into_iter()
+                .map(|(name, kind)| {
+                    let node = Node::Entry {
+                        snapshot: *snapshot,
+                        relative: relative.join(&name),
+                    };
+                    (name, kind, node)
+                })
+                .collect(),
+            None => return reply.error(libc::ENOENT),
+        };
+        let mut listing = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (self.parent(ino), FileType::Directory, OsString::from("..")),
+        ];
+        for (name, kind, node) in entries {
+            let child = self.inode(ino, &name, node);
+            listing.push((child, kind, name));
+        }
+        for (index, (child, kind, name)) in listing.into_iter().enumerate().skip(offset as usize) {
+            // The offset passed back is that of the next entry
+            if reply.add(child, (index + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mounts the snapshots at the given mount point and serves requests until
+/// the filesystem is unmounted
+pub fn mount(snapshots: Vec<(String, Vec<PathBuf>)>, mountpoint: &Path) -> Result<(), String> {
+    let options = [
+        MountOption::RO,
+        MountOption::FSName(String::from("rsbackup")),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(SnapshotFs::new(snapshots), mountpoint, &options)
+        .map_err(|why| format!("Failed to mount {}: {}", mountpoint.display(), why))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::write_layers;
+
+    #[test]
+    fn directories_know_their_parents() {
+        let mut fs = SnapshotFs::new(vec![(String::from("snapshot"), Vec::new())]);
+        let snapshot = fs.inode(ROOT, OsStr::new("snapshot"), Node::Root);
+        let relative = PathBuf::from("dir");
+        let dir = fs.inode(snapshot, OsStr::new("dir"), Node::Entry { snapshot: 0, relative });
+        assert_eq!(fs.parent(ROOT), ROOT);
+        assert_eq!(fs.parent(snapshot), ROOT);
+        assert_eq!(fs.parent(dir), snapshot);
+    }
+
+    #[test]
+    fn newer_files_hide_older_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        write_layers(&new, &old);
+        let fs = SnapshotFs::new(vec![(String::from("snapshot"), vec![new.clone(), old])]);
+        let names = |relative: &str| -> Vec<OsString> {
+            fs.list(0, Path::new(relative)).into_keys().collect()
+        };
+        assert_eq!(fs.resolve(0, Path::new("became_file")).unwrap().0, new.join("became_file"));
+        assert!(fs.resolve(0, Path::new("became_file/child.txt")).is_none());
+        assert!(names("became_file").is_empty());
+        assert_eq!(names("became_dir"), [OsString::from("child.txt")]);
+        assert_eq!(names("").len(), 4);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Reading of configuration files together with the files they pull in with
+// INCLUDE= directives. Like the format module, this is shared by the command
+// line utility and the editor, which turn the entries into their own task
+// models.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::format::{self, Entry};
+
+/// What is found while reading a configuration file
+pub enum Found {
+    /// The start of a file, before any of its entries
+    File,
+    Entry(Entry),
+}
+
+/// Resolves an include directive relative to the directory containing the
+/// including file. Patterns containing wildcards may match any number of
+/// files, which are returned in alphabetical order.
+fn resolve(including: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let path = including
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(pattern);
+    if !pattern.contains(&['*', '?', '['][..]) {
+        return Ok(vec![path]);
+    }
+    let full_pattern = match path.to_str() {
+        Some(full_pattern) => full_pattern,
+        None => return Err(format!("Non-Unicode include pattern {:?}", path)),
+    };
+    match glob::glob(full_pattern) {
+        Ok(paths) => paths
+            .map(|entry| entry.map_err(|why| why.to_string()))
+            .collect(),
+        Err(why) => Err(format!("Invalid include pattern '{}': {}", pattern, why)),
+    }
+}
+
+/// Reads a configuration file and every file it includes in the order in
+/// which they appear. `visit` is called with the path of each file when it is
+/// started and then with each of its entries. Include directives are passed
+/// on before the files they include are read.
+pub fn read(
+    path: &Path,
+    visit: &mut dyn FnMut(&Path, Found) -> Result<(), String>,
+) -> Result<(), String> {
+    read_file(path, &mut Vec::new(), visit)
+}
+
+fn read_file(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    visit: &mut dyn FnMut(&Path, Found) -> Result<(), String>,
+) -> Result<(), String> {
+    let canonical = fs::canonicalize(path).map_err(|why| {
+        format!(
+            "Failed to read configuration file {}: {}",
+            path.display(),
+            why
+        )
+    })?;
+    if stack.contains(&canonical) {
+        let chain: Vec<String> = stack
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(format!("Include cycle detected: {}", chain.join(" -> ")));
+    }
+    let entries = format::read_file(path)?;
+    stack.push(canonical);
+    visit(path, Found::File)?;
+    for entry in entries {
+        let included = match &entry {
+            Entry::Include(pattern) => {
+                resolve(path, pattern).map_err(|err| format!("{}: {}", path.display(), err))?
+            }
+            _ => Vec::new(),
+        };
+        visit(path, Found::Entry(entry))?;
+        for file in included {
+            read_file(&file, stack, visit)?;
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a configuration file and lists the files read and the settings
+    /// found, relative to the given directory
+    fn visit(dir: &Path, path: &str) -> Result<Vec<String>, String> {
+        let mut visited = Vec::new();
+        read(&dir.join(path), &mut |file, found| {
+            let file = file.strip_prefix(dir).unwrap().display();
+            match found {
+                Found::File => visited.push(format!("{}", file)),
+                Found::Entry(Entry::Setting(key, value)) => {
+                    visited.push(format!("{}: {}={}", file, key, value))
+                }
+                Found::Entry(_) => {}
+            }
+            Ok(())
+        })?;
+        Ok(visited)
+    }
+
+    fn write(dir: &Path, path: &str, contents: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn resolves_includes_relative_to_the_including_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "main.conf", "INCLUDE=sub/nested.conf\nHIDDEN=include\n");
+        write(dir.path(), "sub/nested.conf", "INCLUDE=tasks/*.conf\n");
+        write(dir.path(), "sub/tasks/b.conf", "HIDDEN=exclude\n");
+        write(dir.path(), "sub/tasks/a.conf", "HIDDEN=include\n");
+        // Not relative to the main file
+        write(dir.path(), "tasks/c.conf", "HIDDEN=exclude\n");
+
+        assert_eq!(
+            visit(dir.path(), "main.conf").unwrap(),
+            [
+                "main.conf",
+                "sub/nested.conf",
+                "sub/tasks/a.conf",
+                "sub/tasks/a.conf: HIDDEN=include",
+                "sub/tasks/b.conf",
+                "sub/tasks/b.conf: HIDDEN=exclude",
+                "main.conf: HIDDEN=include",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_files_including_themselves() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "main.conf", "INCLUDE=main.conf\n");
+        let err = visit(dir.path(), "main.conf").unwrap_err();
+        let main = fs::canonicalize(dir.path().join("main.conf")).unwrap();
+        let expected = format!("Include cycle detected: {0} -> {0}", main.display());
+        assert_eq!(err, expected);
+    }
+
+    #[test]
+    fn rejects_include_cycles() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.conf", "INCLUDE=sub/b.conf\n");
+        write(dir.path(), "sub/b.conf", "INCLUDE=../a.conf\n");
+        let err = visit(dir.path(), "a.conf").unwrap_err();
+        let canonical = |path: &str| fs::canonicalize(dir.path().join(path)).unwrap();
+        let (a, b) = (canonical("a.conf"), canonical("sub/b.conf"));
+        let (a, b) = (a.display(), b.display());
+        let expected = format!("Include cycle detected: {0} -> {1} -> {0}", a, b);
+        assert_eq!(err, expected);
+    }
+
+    #[test]
+    fn files_may_be_included_more_than_once() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "main.conf", "INCLUDE=a.conf\nINCLUDE=b.conf\n");
+        write(dir.path(), "a.conf", "INCLUDE=common.conf\n");
+        write(dir.path(), "b.conf", "INCLUDE=common.conf\n");
+        write(dir.path(), "common.conf", "");
+        let visited = visit(dir.path(), "main.conf").unwrap();
+        assert_eq!(visited, ["main.conf", "a.conf", "common.conf", "b.conf", "common.conf"]);
+    }
+}
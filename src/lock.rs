@@ -0,0 +1,236 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Advisory locks preventing overlapping runs. Locks are held with flock(2),
+// so they are released by the kernel if rsbackup dies. The lock file records
+// the PID and start time of the holder for reporting and is emptied when the
+// lock is released; a non-empty file found when acquiring the lock was left
+// behind by a process that didn't exit cleanly.
+//
+// Locks are kept in a hidden directory next to the configuration file so that
+// every user running the file shares them, e.g. root's cron jobs and a user
+// running the same file by hand.
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chrono::{DateTime, Local};
+use sha2::{Digest, Sha256};
+
+use crate::schedule;
+
+/// A held lock, released when dropped
+pub struct Lock {
+    file: File,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Replaces characters that could cause problems in file names
+pub fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Identifies a path by a hash of its canonical form, for use in file names
+pub fn path_key(path: &Path) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    Sha256::digest(path.as_os_str().as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn is_writable(dir: &Path) -> bool {
+    match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Directory holding the locks of a configuration file. Users who can't
+/// write next to the file, such as users running the system-wide
+/// configuration, use their state directory instead, where their locks only
+/// exclude their own runs.
+fn lock_dir(config: &Path) -> Result<PathBuf, String> {
+    let config = fs::canonicalize(config).unwrap_or_else(|_| config.to_path_buf());
+    if let (Some(parent), Some(name)) = (config.parent(), config.file_name()) {
+        let dir = parent.join(format!(".{}.locks", name.to_string_lossy()));
+        if fs::create_dir_all(&dir).is_ok() && is_writable(&dir) {
+            return Ok(dir);
+        }
+    }
+    match schedule::state_dir() {
+        Some(dir) => Ok(dir.join("locks").join(path_key(&config))),
+        None => Err(String::from("Could not determine state directory.")),
+    }
+}
+
+/// Path of the lock for a whole run of a configuration file
+pub fn config_lock_path(config: &Path) -> Result<PathBuf, String> {
+    Ok(lock_dir(config)?.join("run.lock"))
+}
+
+/// Path of the lock for the tasks of a configuration file writing to the
+/// given destination. Locks are keyed by destination rather than by ID since
+/// IDs are optional and need not be unique.
+pub fn task_lock_path(config: &Path, destination: &Path) -> Result<PathBuf, String> {
+    Ok(lock_dir(config)?.join(format!("task-{}.lock", path_key(destination))))
+}
+
+/// Describes the process recorded in a lock file
+fn describe(contents: &str) -> Option<String> {
+    let (pid, started) = contents.trim().split_once('\t')?;
+    match DateTime::parse_from_rfc3339(started) {
+        Ok(started) => Some(format!(
+            "PID {}, started {}",
+            pid,
+            started.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+        )),
+        Err(_) => Some(format!("PID {}", pid)),
+    }
+}
+
+/// Describes the process currently holding a lock
+pub fn holder(path: &Path) -> String {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| describe(&contents))
+        .unwrap_or_else(|| String::from("another rsbackup process"))
+}
+
+fn lock(path: &Path, blocking: bool) -> Result<Option<Lock>, String> {
+    if let Some(parent) = path.parent() {
+        if let Err(why) = fs::create_dir_all(parent) {
+            return Err(format!("Failed to create {}: {}", parent.display(), why));
+        }
+    }
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(why) => return Err(format!("Failed to open {}: {}", path.display(), why)),
+    };
+    let operation = match blocking {
+        true => libc::LOCK_EX,
+        false => libc::LOCK_EX | libc::LOCK_NB,
+    };
+    while unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EWOULDBLOCK) => return Ok(None),
+            _ => return Err(format!("Failed to lock {}: {}", path.display(), err)),
+        }
+    }
+    let mut previous = String::new();
+    let _ = file.read_to_string(&mut previous);
+    if let Some(holder) = describe(&previous) {
+        println!("Removing stale lock {} left by {}", path.display(), holder);
+    }
+    let record = format!("{}\t{}\n", process::id(), Local::now().to_rfc3339());
+    let written = file
+        .set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(record.as_bytes()));
+    if let Err(why) = written {
+        return Err(format!("Failed to write {}: {}", path.display(), why));
+    }
+    Ok(Some(Lock { file }))
+}
+
+/// Acquires a lock if it is free
+pub fn try_acquire(path: &Path) -> Result<Option<Lock>, String> {
+    lock(path, false)
+}
+
+/// Waits until a lock is free and acquires it
+pub fn acquire(path: &Path) -> Result<Lock, String> {
+    lock(path, true).map(|lock| lock.expect("Blocking lock returned without locking"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_distinguish_flattened_paths() {
+        assert_ne!(path_key(Path::new("/a_b")), path_key(Path::new("/a/b")));
+        assert_eq!(path_key(Path::new("/a/b")), path_key(Path::new("/a/b")));
+    }
+
+    #[test]
+    fn keys_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        fs::create_dir(&target).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert_eq!(path_key(&target), path_key(&link));
+    }
+
+    #[test]
+    fn locks_are_next_to_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("backup.conf");
+        fs::write(&config, "").unwrap();
+        let lock_path = config_lock_path(&config).unwrap();
+        let expected = fs::canonicalize(dir.path()).unwrap().join(".backup.conf.locks");
+        assert_eq!(lock_path, expected.join("run.lock"));
+        let task_lock = task_lock_path(&config, Path::new("/mnt/backup")).unwrap();
+        assert_eq!(task_lock.parent(), Some(expected.as_path()));
+    }
+
+    #[test]
+    fn tasks_with_other_destinations_lock_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("backup.conf");
+        fs::write(&config, "").unwrap();
+        let first = task_lock_path(&config, &dir.path().join("a")).unwrap();
+        let second = task_lock_path(&config, &dir.path().join("b")).unwrap();
+        let _first = try_acquire(&first).unwrap().unwrap();
+        let _second = try_acquire(&second).unwrap().unwrap();
+        assert!(try_acquire(&first).unwrap().is_none());
+    }
+
+    #[test]
+    fn records_and_releases_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.lock");
+        let lock = try_acquire(&path).unwrap().unwrap();
+        assert!(holder(&path).starts_with(&format!("PID {}, started ", process::id())));
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert!(try_acquire(&path).unwrap().is_some());
+    }
+}
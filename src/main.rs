@@ -15,48 +15,264 @@
 use std::io;
 use std::io::Write;
 
-use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
 use std::process;
+use std::sync::{Condvar, Mutex};
+use std::thread;
 
-use std::path::PathBuf;
+use chrono::{Local, TimeZone};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
+mod archive;
+mod config;
+mod crypto;
+mod daemon;
+mod diff;
+mod find;
+mod format;
+#[cfg(feature = "fuse")]
+mod fuse;
+mod include;
+mod lock;
+mod manifest;
+mod mounts;
+mod priority;
+mod restore;
+mod rsync_args;
+mod schedule;
 mod task;
+#[cfg(test)]
+mod testutil;
+mod timers;
+
+use task::{blocking_prerequisite, Outcomes, TaskOutcome};
+
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    /// Show which configuration file is used
+    Path,
+    /// Move the legacy ~/.arcutillib/backup.conf to the XDG configuration directory
+    Migrate,
+    /// Convert between the line and TOML formats based on the file extensions.
+    /// Comments are not carried over.
+    Convert {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct ScheduleTarget {
+    /// Use systemd user timers
+    #[structopt(long, required_unless = "cron", conflicts_with = "cron")]
+    systemd: bool,
+    /// Use the user crontab
+    #[structopt(long)]
+    cron: bool,
+    /// Write the generated files to this directory instead of installing them
+    #[structopt(long, parse(from_os_str))]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+enum ScheduleCommand {
+    /// Generate and install a job for every task with a SCHEDULE
+    Install(ScheduleTarget),
+    /// Remove all jobs installed by rsbackup
+    Uninstall(ScheduleTarget),
+    /// Show which scheduled tasks have a job installed
+    Status(ScheduleTarget),
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Run the backup tasks (default if no command is given)
+    Run {
+        /// Only run the task with this ID (can be repeated)
+        #[structopt(long, number_of_values = 1)]
+        only: Vec<String>,
+    },
+    /// Inspect or manage the configuration file
+    Config(ConfigCommand),
+    /// Check the configuration for errors and potential problems
+    Check,
+    /// Run tasks with a SCHEDULE as they become due (reload with SIGHUP)
+    Daemon,
+    /// Generate systemd timers or cron entries for tasks with a SCHEDULE
+    Schedule(ScheduleCommand),
+    /// Compare the source of a task with its destination or a snapshot
+    Verify {
+        /// ID of the task to verify
+        task: String,
+        /// Snapshot of a backup task to verify, by name or "latest"
+        #[structopt(long, default_value = "latest")]
+        snapshot: String,
+        /// Compare the contents of all files instead of sizes and times
+        #[structopt(long)]
+        checksum: bool,
+    },
+    /// Check the snapshots of backup tasks against their checksum manifests
+    Scrub {
+        /// ID of the task to scrub (all backup tasks if omitted)
+        task: Option<String>,
+    },
+    /// Show the files that changed between two snapshots of a backup task
+    Diff {
+        /// ID of the backup task
+        task: String,
+        /// Older snapshot, by name or "latest"
+        from: String,
+        /// Newer snapshot, by name or "latest", or "source" to compare with
+        /// the task's source (default: latest)
+        to: Option<String>,
+        /// Only show files under this path, relative to the compared directories
+        #[structopt(long)]
+        prefix: Option<String>,
+        /// Print the differences as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// List the versions of a file found in the snapshots of a backup task
+    Find {
+        /// ID of the backup task
+        task: String,
+        /// Path of the file relative to the snapshots, or a glob pattern
+        pattern: String,
+    },
+    /// Build a complete tree of a snapshot, adding the files from earlier
+    /// snapshots for tasks using [COMPARE BPATH]
+    Materialize {
+        /// ID of the backup task
+        task: String,
+        /// Snapshot to reconstruct, by name or "latest"
+        snapshot: String,
+        /// Directory to create the tree in (must not exist or be empty)
+        #[structopt(long, parse(from_os_str))]
+        to: PathBuf,
+    },
+    /// Write a snapshot of a backup task to a compressed tar archive
+    Export {
+        /// ID of the backup task
+        task: String,
+        /// Snapshot to export, by name or "latest"
+        snapshot: String,
+        /// Path of the archive to create (.tar.zst)
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Unpack a snapshot archive into the destination of a backup task
+    Import {
+        /// ID of the backup task
+        task: String,
+        /// Archive created with the export command
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Generate a key file for tasks with ENCRYPT=keyfile
+    Keygen {
+        /// Path of the key file to create
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Mount the snapshots of a backup task as a read-only filesystem
+    #[cfg(feature = "fuse")]
+    Mount {
+        /// ID of the backup task
+        task: String,
+        /// Directory to mount the snapshots at
+        #[structopt(parse(from_os_str))]
+        mountpoint: PathBuf,
+    },
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "rsbackup", about = "rsync backup utility written in Rust")]
 struct Options {
-    #[structopt(short = "f", long = "conf", parse(from_os_str))]
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+
+    #[structopt(short = "f", long = "conf", parse(from_os_str), global = true)]
     config: Option<PathBuf>,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     ask: bool,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     debug: bool,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     dry_run: bool,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     link: bool,
 
-    #[structopt(short, long)]
+    #[structopt(short, long, global = true)]
     quiet: bool,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     up_only: bool,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     id_tasks: bool,
 
-    #[structopt(short = "s", long = "safe")]
+    #[structopt(short = "s", long = "safe", global = true)]
     quit_on_fail: bool,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     download: bool,
+
+    /// Wait for tasks locked by another rsbackup process instead of skipping
+    /// them (default when running in a terminal)
+    #[structopt(long, global = true, conflicts_with = "no-wait")]
+    wait: bool,
+
+    /// Skip tasks locked by another rsbackup process
+    #[structopt(long, global = true)]
+    no_wait: bool,
+
+    /// Number of tasks to run at the same time. Tasks with destinations on the
+    /// same device never run at the same time.
+    #[structopt(short = "j", long, default_value = "1", global = true)]
+    jobs: usize,
+
+    /// Don't compare the estimated transfer size with the free space on the
+    /// destination before running tasks
+    #[structopt(long, global = true)]
+    no_space_check: bool,
+
+    /// Back up hidden files unless HIDDEN is set by the task or the configuration
+    #[structopt(long, global = true)]
+    include_hidden: bool,
+
+    /// Additional rsync option for every task, e.g. --rsync-arg=--checksum
+    #[structopt(
+        long = "rsync-arg",
+        number_of_values = 1,
+        allow_hyphen_values = true,
+        global = true
+    )]
+    rsync_args: Vec<String>,
+
+    /// Bandwidth limit for every task, overriding BWLIMIT and WORK_BWLIMIT,
+    /// e.g. 500K or 2M
+    #[structopt(long, parse(try_from_str = priority::parse_bwlimit), global = true)]
+    bwlimit: Option<String>,
+
+    /// Niceness of rsync for every task, overriding NICE
+    #[structopt(
+        long,
+        parse(try_from_str = priority::parse_nice),
+        allow_hyphen_values = true,
+        global = true
+    )]
+    nice: Option<i32>,
+
+    /// IO priority of rsync for every task, overriding IONICE, e.g. idle or
+    /// best-effort:7
+    #[structopt(long, global = true)]
+    ionice: Option<priority::IoPriority>,
 }
 
 fn operation_failed(err: &str, qof: bool) -> bool {
@@ -94,79 +310,1260 @@ fn get_yn(prompt: &str, default: bool) -> bool {
     }
 }
 
-fn run_backup(opt: &Options) -> bool {
+fn run_options(opt: &Options) -> task::RunOptions {
+    task::RunOptions {
+        quiet: opt.quiet,
+        debug: opt.debug,
+        dry_run: opt.dry_run,
+        include_hidden: opt.include_hidden,
+        rsync_args: opt.rsync_args.clone(),
+        bwlimit: opt.bwlimit.clone(),
+        nice: opt.nice,
+        ionice: opt.ionice,
+        rsync: PathBuf::from("rsync"),
+    }
+}
+
+fn config_path(opt: &Options) -> Option<PathBuf> {
+    match config::find_config(opt.config.as_deref()) {
+        Ok((path, source)) => {
+            if opt.debug {
+                println!("Using configuration file {} ({})", path.display(), source);
+            }
+            if Some(&path) == config::legacy_config_path().as_ref() {
+                println!(
+                    "Using legacy configuration file {}. Run `rsbackup config migrate` to move it.",
+                    path.display()
+                );
+            }
+            Some(path)
+        }
+        Err(err) => {
+            operation_failed(&err, true);
+            None
+        }
+    }
+}
+
+fn check_config(opt: &Options) -> bool {
+    let path = match config_path(opt) {
+        Some(path) => path,
+        None => return false,
+    };
+    let tasks = match config::load_tasks(&path, opt.debug) {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            println!("Configuration error: {}", err);
+            return false;
+        }
+    };
+    let options = run_options(opt);
+    let mut warnings = 0;
+    for task in &tasks {
+        for warning in task.check(&options) {
+            println!("{}: {}", task.get_id(), warning);
+            warnings += 1;
+        }
+    }
+    println!("Checked {} tasks: {} warnings", tasks.len(), warnings);
+    true
+}
+
+/// Asks for confirmation if needed before running a task, returning the
+/// outcome if the task shouldn't run. Tasks that need confirmation are
+/// skipped when running non-interactively.
+fn prepare_task(
+    opt: &Options,
+    options: &task::RunOptions,
+    task: &task::Task,
+    interactive: bool,
+) -> Result<(), TaskOutcome> {
+    if task.is_update_task() {
+        println!("Found update task.");
+    } else {
+        println!("Found backup task.");
+    }
+    if opt.id_tasks {
+        println!("Task ID: {}", task.get_id());
+    }
+    if let Err(why) = task.check_destination() {
+        println!("Destination unavailable: {}", why);
+        return Err(TaskOutcome::Skipped(format!("Destination unavailable: {}", why)));
+    }
+    if opt.ask || task.should_confirm() {
+        if !interactive {
+            return Err(TaskOutcome::Skipped(String::from(
+                "Task requires confirmation",
+            )));
+        }
+        let prompt = format!(
+            "{} {}\nRun task?",
+            match task.is_update_task() {
+                true => match opt.download {
+                    true => "Download",
+                    false => "Upload",
+                },
+                false => "Backup",
+            },
+            task.get_description()
+        );
+        if !get_yn(&prompt, true) {
+            return Err(TaskOutcome::Skipped(String::from("Declined by user")));
+        }
+    }
+    if !opt.debug && !opt.dry_run {
+        if let Err(why) = task.unlock(interactive) {
+            println!("{}", why);
+            return Err(TaskOutcome::Failed(why));
+        }
+    }
+    if task.is_mirror() && !opt.debug {
+        match task.preview_deletions(options) {
+            Ok(preview) => {
+                println!(
+                    "Mirror will delete {} of {} files in the destination ({:.1}%)",
+                    preview.deletions.len(),
+                    preview.total,
+                    preview.percentage()
+                );
+                if !opt.quiet {
+                    for path in &preview.deletions {
+                        println!("  {}", path);
+                    }
+                }
+                if preview.percentage() > task.get_delete_threshold()
+                    && (!interactive
+                        || !get_yn("Deletions exceed the threshold. Run task?", false))
+                {
+                    return Err(TaskOutcome::Skipped(String::from(
+                        "Mirror deletions exceed the threshold",
+                    )));
+                }
+            }
+            Err(why) => {
+                return Err(TaskOutcome::Failed(format!(
+                    "Mirror preview failed: {}",
+                    why
+                )));
+            }
+        }
+    }
+    if !opt.no_space_check && !opt.debug && !opt.dry_run {
+        let estimate = match task.estimate_space(options) {
+            Ok(estimate) => estimate,
+            Err(why) => {
+                return Err(TaskOutcome::Failed(format!(
+                    "Free space check failed: {}",
+                    why
+                )));
+            }
+        };
+        if !estimate.fits() {
+            let why = format!(
+                "Transfer of {} needs more than the {} free on the destination{}",
+                task::format_size(estimate.required),
+                task::format_size(estimate.available),
+                match estimate.reserve {
+                    0 => String::new(),
+                    reserve => format!(" with {} kept free", task::format_size(reserve)),
+                }
+            );
+            println!("{}", why);
+            if !interactive {
+                return Err(TaskOutcome::Failed(format!("Not enough free space: {}", why)));
+            }
+            if !get_yn("The backup may not fit. Run task anyway?", false) {
+                return Err(TaskOutcome::Skipped(String::from("Declined by user")));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a single task, asking for confirmation if needed
+fn execute_task(
+    opt: &Options,
+    options: &task::RunOptions,
+    task: &task::Task,
+    interactive: bool,
+) -> TaskOutcome {
+    if let Err(outcome) = prepare_task(opt, options, task, interactive) {
+        return outcome;
+    }
+    match task.run_task(options) {
+        Ok(retries) => TaskOutcome::Completed(retries),
+        Err(why) => TaskOutcome::Failed(format!("Backup failed: {}", why)),
+    }
+}
+
+/// Runs a single task non-interactively unless another process holds its lock
+fn execute_locked_task(
+    opt: &Options,
+    options: &task::RunOptions,
+    config: &Path,
+    task: &task::Task,
+) -> TaskOutcome {
+    let lock_path = match lock::task_lock_path(config, task.get_destination()) {
+        Ok(path) => path,
+        Err(err) => return TaskOutcome::Failed(err),
+    };
+    match lock::try_acquire(&lock_path) {
+        Ok(Some(_lock)) => execute_task(opt, options, task, false),
+        Ok(None) => TaskOutcome::Skipped(format!("Locked by {}", lock::holder(&lock_path))),
+        Err(err) => TaskOutcome::Failed(err),
+    }
+}
+
+/// Acquires the lock for a full run of a configuration file, which prevents
+/// a run from starting while the previous one is still in progress
+fn lock_config(config: &Path, wait: bool) -> Result<lock::Lock, String> {
+    let lock_path = lock::config_lock_path(config)?;
+    if let Some(lock) = lock::try_acquire(&lock_path)? {
+        return Ok(lock);
+    }
+    let holder = lock::holder(&lock_path);
+    if !wait {
+        return Err(format!(
+            "Another run of {} is in progress ({}). Use --wait to wait for it to finish.",
+            config.display(),
+            holder
+        ));
+    }
+    println!(
+        "Waiting for another run of {} to finish ({})",
+        config.display(),
+        holder
+    );
+    lock::acquire(&lock_path)
+}
+
+fn run_daemon(opt: &Options) -> bool {
+    let path = match config_path(opt) {
+        Some(path) => path,
+        None => return false,
+    };
+    let options = run_options(opt);
+    daemon::run(
+        &path,
+        || config::load_tasks(&path, opt.debug),
+        |task| execute_locked_task(opt, &options, &path, task),
+    )
+}
+
+/// Runs tasks one at a time in order. Tasks locked by another process are
+/// queued until the end of the run along with the tasks that depend on them.
+fn run_serial<'a>(
+    opt: &Options,
+    options: &task::RunOptions,
+    config: &Path,
+    tasks: &[&'a task::Task],
+    wait: bool,
+) -> Outcomes<'a> {
+    let mut outcomes: Outcomes = Vec::new();
+    let mut queued: Vec<(&task::Task, PathBuf)> = Vec::new();
+    let mut stopped = false;
+    for &task in tasks {
+        if let Some(why) = blocking_prerequisite(task, &outcomes) {
+            println!("Skipping task {}: {}", task.get_id(), why);
+            outcomes.push((task, TaskOutcome::Skipped(why)));
+            continue;
+        }
+        let lock_path = match lock::task_lock_path(config, task.get_destination()) {
+            Ok(path) => path,
+            Err(err) => {
+                println!("{}", err);
+                return outcomes;
+            }
+        };
+        if queued.iter().any(|(other, _)| {
+            task.get_prerequisites().iter().any(|id| id == other.get_id())
+        }) {
+            println!("Task {} is queued after its prerequisites", task.get_id());
+            queued.push((task, lock_path));
+            continue;
+        }
+        let outcome = match lock::try_acquire(&lock_path) {
+            Ok(Some(_lock)) => execute_task(opt, options, task, true),
+            Ok(None) if wait => {
+                println!(
+                    "Task {} is locked by {}; queued until it is released",
+                    task.get_id(),
+                    lock::holder(&lock_path)
+                );
+                queued.push((task, lock_path));
+                continue;
+            }
+            Ok(None) => {
+                let why = format!("Locked by {}", lock::holder(&lock_path));
+                println!("Skipping task {}: {}", task.get_id(), why);
+                TaskOutcome::Skipped(why)
+            }
+            Err(err) => TaskOutcome::Failed(err),
+        };
+        if let TaskOutcome::Failed(err) = &outcome {
+            stopped = operation_failed(err, opt.quit_on_fail);
+        }
+        outcomes.push((task, outcome));
+        if stopped {
+            return outcomes;
+        }
+    }
+    for (task, lock_path) in queued {
+        if let Some(why) = blocking_prerequisite(task, &outcomes) {
+            println!("Skipping task {}: {}", task.get_id(), why);
+            outcomes.push((task, TaskOutcome::Skipped(why)));
+            continue;
+        }
+        println!("Waiting for task {} to be released", task.get_id());
+        let outcome = match lock::acquire(&lock_path) {
+            Ok(_lock) => execute_task(opt, options, task, true),
+            Err(err) => TaskOutcome::Failed(err),
+        };
+        if let TaskOutcome::Failed(err) = &outcome {
+            stopped = operation_failed(err, opt.quit_on_fail);
+        }
+        outcomes.push((task, outcome));
+        if stopped {
+            break;
+        }
+    }
+    outcomes
+}
+
+fn print_labeled(id: &str, text: &str) {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for line in text.lines() {
+        // Only keep the final state of progress lines redrawn with \r
+        let line = line.rsplit('\r').next().unwrap_or(line);
+        let _ = writeln!(out, "[{}] {}", id, line);
+    }
+}
+
+/// Runs a task in a parallel run once it has been scheduled, waiting for its
+/// lock if requested
+fn run_parallel_task(
+    options: &task::RunOptions,
+    config: &Path,
+    task: &task::Task,
+    wait: bool,
+) -> TaskOutcome {
+    let id = task.get_id();
+    let lock_path = match lock::task_lock_path(config, task.get_destination()) {
+        Ok(path) => path,
+        Err(err) => return TaskOutcome::Failed(err),
+    };
+    let _lock = match lock::try_acquire(&lock_path) {
+        Ok(Some(lock)) => lock,
+        Ok(None) if wait => {
+            print_labeled(
+                id,
+                &format!(
+                    "Locked by {}; waiting until it is released",
+                    lock::holder(&lock_path)
+                ),
+            );
+            match lock::acquire(&lock_path) {
+                Ok(lock) => lock,
+                Err(err) => return TaskOutcome::Failed(err),
+            }
+        }
+        Ok(None) => {
+            return TaskOutcome::Skipped(format!("Locked by {}", lock::holder(&lock_path)));
+        }
+        Err(err) => return TaskOutcome::Failed(err),
+    };
+    print_labeled(id, "Started");
+    let (result, output) = task.run_task_buffered(options);
+    print_labeled(id, &output);
+    match result {
+        Ok(retries) => TaskOutcome::Completed(retries),
+        Err(why) => TaskOutcome::Failed(format!("Backup failed: {}", why)),
+    }
+}
+
+/// Tasks of a parallel run that haven't finished yet
+struct ParallelRun<'a> {
+    pending: Vec<(&'a task::Task, String)>,
+    running: Vec<(&'a task::Task, String)>,
+    outcomes: Outcomes<'a>,
+    stopped: bool,
+}
+
+impl<'a> ParallelRun<'a> {
+    /// Takes the first pending task that can start: its prerequisites have
+    /// finished and no other task with a destination on the same device is
+    /// running or comes before it. Tasks whose prerequisites didn't complete
+    /// are skipped along the way.
+    fn next_task(&mut self) -> Option<(&'a task::Task, String)> {
+        let mut index = 0;
+        while index < self.pending.len() {
+            let (task, group) = &self.pending[index];
+            if let Some(why) = blocking_prerequisite(task, &self.outcomes) {
+                print_labeled(task.get_id(), &format!("Skipping task: {}", why));
+                let (task, _) = self.pending.remove(index);
+                self.outcomes.push((task, TaskOutcome::Skipped(why)));
+                continue;
+            }
+            let unfinished = |other: &task::Task| {
+                task.get_prerequisites().iter().any(|id| id == other.get_id())
+            };
+            let ready = !self.running.iter().any(|(other, other_group)| {
+                other_group == group || unfinished(other)
+            }) && !self.pending[..index].iter().any(|(other, other_group)| {
+                other_group == group || unfinished(other)
+            });
+            if ready {
+                return Some(self.pending.remove(index));
+            }
+            index += 1;
+        }
+        None
+    }
+}
+
+/// Runs up to `opt.jobs` tasks at a time. Tasks whose destinations are on the
+/// same device run one after another in their original order, and tasks wait
+/// for their prerequisites. All prompts are shown before any task starts, and
+/// the output of each task is printed with its ID once it finishes. Failures
+/// can't be confirmed interactively while other tasks are running, so the
+/// remaining tasks continue unless --safe is given.
+fn run_parallel<'a>(
+    opt: &Options,
+    options: &task::RunOptions,
+    config: &Path,
+    tasks: &[&'a task::Task],
+    wait: bool,
+) -> Outcomes<'a> {
+    let mut outcomes: Outcomes = Vec::new();
+    let mut pending = Vec::new();
+    for &task in tasks {
+        // Don't ask about tasks that can't run anyway
+        if let Some(why) = blocking_prerequisite(task, &outcomes) {
+            outcomes.push((task, TaskOutcome::Skipped(why)));
+            continue;
+        }
+        match prepare_task(opt, options, task, true) {
+            Ok(_) => pending.push((task, task.destination_group())),
+            Err(outcome) => {
+                let stop = match &outcome {
+                    TaskOutcome::Failed(err) => operation_failed(err, opt.quit_on_fail),
+                    _ => false,
+                };
+                outcomes.push((task, outcome));
+                if stop {
+                    return outcomes;
+                }
+            }
+        }
+    }
+    let run = Mutex::new(ParallelRun {
+        pending,
+        running: Vec::new(),
+        outcomes,
+        stopped: false,
+    });
+    let changed = Condvar::new();
+    thread::scope(|scope| {
+        for _ in 0..opt.jobs {
+            scope.spawn(|| {
+                let mut state = run.lock().unwrap();
+                while !state.stopped && !state.pending.is_empty() {
+                    let (task, group) = match state.next_task() {
+                        Some(next) => next,
+                        // The remaining tasks may all have been skipped
+                        None if state.pending.is_empty() => {
+                            changed.notify_all();
+                            break;
+                        }
+                        None => {
+                            state = changed.wait(state).unwrap();
+                            continue;
+                        }
+                    };
+                    state.running.push((task, group));
+                    drop(state);
+                    let outcome = run_parallel_task(options, config, task, wait);
+                    match &outcome {
+                        TaskOutcome::Completed(retries) => print_labeled(
+                            task.get_id(),
+                            &format!("Completed{}", task::describe_retries(*retries)),
+                        ),
+                        TaskOutcome::Skipped(why) => {
+                            print_labeled(task.get_id(), &format!("Skipping task: {}", why))
+                        }
+                        TaskOutcome::Failed(err) => print_labeled(task.get_id(), err),
+                    }
+                    state = run.lock().unwrap();
+                    if let TaskOutcome::Failed(_) = outcome {
+                        state.stopped |= opt.quit_on_fail;
+                    }
+                    state.running.retain(|(other, _)| !std::ptr::eq(*other, task));
+                    state.outcomes.push((task, outcome));
+                    changed.notify_all();
+                }
+            });
+        }
+    });
+    run.into_inner().unwrap().outcomes
+}
+
+/// Lists the outcome of every task in the run
+/// Prints the outcome of every task. Returns whether none of them failed.
+fn print_summary(tasks: &[&task::Task], outcomes: &Outcomes) -> bool {
+    println!("Summary:");
+    let (mut completed, mut skipped, mut failed) = (0, 0, 0);
+    for &task in tasks {
+        let outcome = outcomes
+            .iter()
+            .find(|(other, _)| std::ptr::eq(*other, task))
+            .map(|(_, outcome)| outcome);
+        match outcome {
+            Some(TaskOutcome::Completed(retries)) => {
+                println!(
+                    "  {}: completed{}",
+                    task.get_id(),
+                    task::describe_retries(*retries)
+                );
+                completed += 1;
+            }
+            Some(TaskOutcome::Skipped(why)) => {
+                println!("  {}: skipped ({})", task.get_id(), why);
+                skipped += 1;
+            }
+            Some(TaskOutcome::Failed(why)) => {
+                println!("  {}: failed ({})", task.get_id(), why);
+                failed += 1;
+            }
+            None => {
+                println!("  {}: not run", task.get_id());
+                skipped += 1;
+            }
+        }
+    }
+    println!(
+        "{} completed, {} skipped, {} failed",
+        completed, skipped, failed
+    );
+    failed == 0
+}
+
+fn run_backup(opt: &Options, only: &[String]) -> bool {
     if opt.debug {
         println!("Running in debug mode...");
     }
-    let path = match &opt.config {
-        Some(path) => path.as_path(),
-        None => {
-            println!("No configuration file specified. Defaulting to ~/.arcutillib/backup.conf");
-            Path::new("~/.arcutillib/backup.conf")
+    let path = match config_path(opt) {
+        Some(path) => path,
+        None => return false,
+    };
+    let options = run_options(opt);
+    for arg in &options.rsync_args {
+        if let Err(err) = rsync_args::check_rsync_arg(arg) {
+            println!("{}", err);
+            return false;
+        }
+    }
+    let tasks = match config::load_tasks(&path, opt.debug) {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            println!("Failed to construct task: {}", err);
+            return false;
+        }
+    };
+    for id in only {
+        if !tasks.iter().any(|task| task.get_id() == id) {
+            println!("No task with ID {}", id);
+            return false;
+        }
+    }
+    if opt.jobs == 0 {
+        println!("The number of jobs must be at least 1");
+        return false;
+    }
+    let wait = opt.wait || (!opt.no_wait && unsafe { libc::isatty(libc::STDIN_FILENO) } == 1);
+    // Runs of individual tasks, as started by timers, only take the task locks
+    let _config_lock = match only.is_empty() {
+        true => match lock_config(&path, wait) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                println!("{}", err);
+                return false;
+            }
+        },
+        false => None,
+    };
+    let tasks: Vec<&task::Task> = tasks
+        .iter()
+        .filter(|task| only.is_empty() || only.iter().any(|id| id == task.get_id()))
+        .filter(|task| task.is_update_task() || !opt.up_only)
+        .collect();
+    let outcomes = match opt.jobs {
+        1 => run_serial(opt, &options, &path, &tasks, wait),
+        _ => run_parallel(opt, &options, &path, &tasks, wait),
+    };
+    if !print_summary(&tasks, &outcomes) {
+        return false;
+    }
+    println!("Backup complete.");
+    true
+}
+
+/// Loads the configuration and finds the task with the given ID
+fn load_task(opt: &Options, id: &str) -> Option<task::Task> {
+    let path = config_path(opt)?;
+    let tasks = match config::load_tasks(&path, opt.debug) {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            println!("Configuration error: {}", err);
+            return None;
+        }
+    };
+    let task = tasks.into_iter().find(|task| task.get_id() == id);
+    if task.is_none() {
+        println!("No task with ID {}", id);
+    }
+    task
+}
+
+fn verify_task(opt: &Options, id: &str, snapshot: &str, checksum: bool) -> bool {
+    let task = match load_task(opt, id) {
+        Some(task) => task,
+        None => return false,
+    };
+    if task.is_encrypted() {
+        return verify_encrypted(opt, &task, snapshot, checksum);
+    }
+    let target = match task.is_update_task() {
+        true => task.get_destination().to_path_buf(),
+        false => match task.snapshot_path(snapshot) {
+            Ok(path) => path,
+            Err(err) => {
+                println!("{}", err);
+                return false;
+            }
+        },
+    };
+    println!("Verifying {}", target.display());
+    report_verification(opt, &task, &target, checksum)
+}
+
+/// Verifies a snapshot of an encrypted task by decrypting it into a local
+/// temporary directory
+fn verify_encrypted(opt: &Options, task: &task::Task, snapshot: &str, checksum: bool) -> bool {
+    if let Err(err) = task.unlock(true) {
+        println!("{}", err);
+        return false;
+    }
+    let target = schedule::state_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("verify-{}", process::id()));
+    let verified = match task.extract_snapshot(snapshot, &target) {
+        Ok(name) => {
+            println!("Verifying decrypted snapshot {}", name);
+            report_verification(opt, task, &target, checksum)
+        }
+        Err(err) => {
+            println!("{}", err);
+            false
+        }
+    };
+    let _ = std::fs::remove_dir_all(&target);
+    verified
+}
+
+fn report_verification(opt: &Options, task: &task::Task, target: &Path, checksum: bool) -> bool {
+    let verification = match task.verify(&run_options(opt), target, checksum) {
+        Ok(verification) => verification,
+        Err(err) => {
+            println!("Verification failed: {}", err);
+            return false;
         }
     };
-    let result = File::open(&path);
-    if let Err(why) = result {
-        let err = format!("Failed to read configuration file: {}", why);
-        operation_failed(&err, true);
+    for path in &verification.missing {
+        println!("Missing: {}", path);
+    }
+    for path in &verification.extra {
+        println!("Extra: {}", path);
+    }
+    for (path, changes) in &verification.differing {
+        match changes.is_empty() {
+            true => println!("Differs: {}", path),
+            false => println!("Differs ({}): {}", changes.join(", "), path),
+        }
+    }
+    println!(
+        "{} missing, {} extra, {} differing files",
+        verification.missing.len(),
+        verification.extra.len(),
+        verification.differing.len()
+    );
+    verification.is_clean()
+}
+
+fn scrub_snapshots(opt: &Options, id: Option<&str>) -> bool {
+    let path = match config_path(opt) {
+        Some(path) => path,
+        None => return false,
+    };
+    let tasks = match config::load_tasks(&path, opt.debug) {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            println!("Configuration error: {}", err);
+            return false;
+        }
+    };
+    let tasks: Vec<&task::Task> = tasks
+        .iter()
+        .filter(|task| !task.is_update_task())
+        .filter(|task| id.is_none_or(|id| id == task.get_id()))
+        .collect();
+    if let (Some(id), true) = (id, tasks.is_empty()) {
+        println!("No backup task with ID {}", id);
         return false;
     }
-    let config = result.unwrap();
-    let mut config_reader = BufReader::new(config);
-    loop {
-        match task::Task::from_reader(&mut config_reader, opt.debug) {
-            Ok(task) => {
-                if task.is_update_task() {
-                    println!("Found update task.");
-                } else {
-                    if opt.up_only {
-                        continue;
+    let (mut scrubbed, mut problems) = (0, 0);
+    for task in tasks {
+        // Hard-linked files are shared between snapshots of the same task
+        let mut cache = manifest::InodeCache::default();
+        if task.is_encrypted() {
+            // Encrypted snapshots are authenticated, so decrypting them is
+            // enough to detect corruption
+            if let Err(err) = task.unlock(true) {
+                println!("{}: {}", task.get_id(), err);
+                problems += 1;
+                continue;
+            }
+            for snapshot in task.snapshots() {
+                if let Err(err) = task.check_snapshot(&snapshot) {
+                    println!("{}/{}: {}", task.get_id(), snapshot, err);
+                    problems += 1;
+                }
+                scrubbed += 1;
+            }
+            continue;
+        }
+        for snapshot in task.snapshots() {
+            let snapshot_path = task.get_destination().join(&snapshot);
+            if !manifest::manifest_path(&snapshot_path).exists() {
+                println!("{}/{}: no manifest, skipping", task.get_id(), snapshot);
+                continue;
+            }
+            match manifest::scrub(&snapshot_path, &mut cache) {
+                Ok(found) => {
+                    for problem in &found {
+                        println!("{}/{}: {}", task.get_id(), snapshot, problem.describe());
                     }
-                    println!("Found backup task.");
+                    scrubbed += 1;
+                    problems += found.len();
                 }
-                if opt.id_tasks {
-                    println!("Task ID: {}", task.get_id());
+                Err(err) => {
+                    println!("{}/{}: scrub failed: {}", task.get_id(), snapshot, err);
+                    problems += 1;
                 }
-                if opt.ask || task.should_confirm() {
-                    let prompt = format!(
-                        "{} {}\nRun task?",
-                        match task.is_update_task() {
-                            true => match opt.download {
-                                true => "Download",
-                                false => "Upload",
-                            },
-                            false => "Backup",
-                        },
-                        task.get_description()
+            }
+        }
+    }
+    println!("Scrubbed {} snapshots: {} problems", scrubbed, problems);
+    problems == 0
+}
+
+fn diff_snapshots(
+    opt: &Options,
+    id: &str,
+    from: &str,
+    to: &str,
+    prefix: Option<&str>,
+    json: bool,
+) -> bool {
+    let task = match load_task(opt, id) {
+        Some(task) => task,
+        None => return false,
+    };
+    if task.is_encrypted() {
+        println!("Snapshots of task {} are encrypted. Use materialize to decrypt them.", id);
+        return false;
+    }
+    let old = match task.snapshot_layers(from) {
+        Ok(layers) => layers,
+        Err(err) => {
+            println!("{}", err);
+            return false;
+        }
+    };
+    let (old, new) = match to {
+        "source" => match task.source_tree(&old) {
+            Ok((src, old)) => (old, vec![src]),
+            Err(err) => {
+                println!("{}", err);
+                return false;
+            }
+        },
+        to => match task.snapshot_layers(to) {
+            Ok(new) => (old, new),
+            Err(err) => {
+                println!("{}", err);
+                return false;
+            }
+        },
+    };
+    let trees = diff::tree(&old, prefix).and_then(|old_tree| {
+        let mut new_tree = diff::tree(&new, prefix)?;
+        if to == "source" {
+            let included = task.source_files(&run_options(opt))?;
+            new_tree.retain(|path, _| included.contains(path));
+        }
+        Ok((old_tree, new_tree))
+    });
+    let differences = match trees {
+        Ok((old_tree, new_tree)) => diff::compare(old_tree, new_tree),
+        Err(err) => {
+            println!("Comparison failed: {}", err);
+            return false;
+        }
+    };
+    let (from, to) = (old[0].display().to_string(), new[0].display().to_string());
+    if json {
+        println!("{}", diff::to_json(&from, &to, &differences));
+        return true;
+    }
+    println!("Comparing {} with {}", from, to);
+    let mut counts = [0; 3];
+    let mut total = 0;
+    for difference in &differences {
+        let (label, count) = match difference.change {
+            diff::Change::Added => ("Added", &mut counts[0]),
+            diff::Change::Removed => ("Removed", &mut counts[1]),
+            diff::Change::Modified => ("Modified", &mut counts[2]),
+        };
+        *count += 1;
+        total += difference.delta();
+        println!(
+            "{}: {} ({})",
+            label,
+            difference.path,
+            diff::format_delta(difference.delta())
+        );
+    }
+    println!(
+        "{} added, {} removed, {} modified files, {} in total",
+        counts[0],
+        counts[1],
+        counts[2],
+        diff::format_delta(total)
+    );
+    true
+}
+
+fn find_file(opt: &Options, id: &str, pattern: &str) -> bool {
+    let task = match load_task(opt, id) {
+        Some(task) => task,
+        None => return false,
+    };
+    if task.is_update_task() {
+        println!("Task {} is an update task and has no snapshots.", id);
+        return false;
+    }
+    if task.is_encrypted() {
+        println!("Snapshots of task {} are encrypted. Use materialize to decrypt them.", id);
+        return false;
+    }
+    let snapshots = task.snapshot_dirs();
+    let mut cache = manifest::InodeCache::from_manifests(task.get_destination());
+    let files = match find::find(&snapshots, pattern, &mut cache) {
+        Ok(files) => files,
+        Err(err) => {
+            println!("Search failed: {}", err);
+            return false;
+        }
+    };
+    if files.is_empty() {
+        println!("No files matching {} in {} snapshots", pattern, snapshots.len());
+        return false;
+    }
+    for (path, versions) in &files {
+        match versions.len() {
+            1 => println!("{}: 1 version", path),
+            count => println!("{}: {} versions", path, count),
+        }
+        for version in versions {
+            let mtime = match Local.timestamp_opt(version.mtime, 0).single() {
+                Some(mtime) => mtime.format("%Y-%m-%d %H:%M:%S").to_string(),
+                None => version.mtime.to_string(),
+            };
+            println!(
+                "  {}, modified {}, in {}",
+                task::format_size(version.size),
+                mtime,
+                version.snapshots.join(", ")
+            );
+            println!("    {}", version.path.display());
+        }
+    }
+    true
+}
+
+fn materialize_snapshot(opt: &Options, id: &str, snapshot: &str, to: &Path) -> bool {
+    let task = match load_task(opt, id) {
+        Some(task) => task,
+        None => return false,
+    };
+    if task.is_encrypted() {
+        let extracted = task
+            .unlock(true)
+            .and_then(|_| task.extract_snapshot(snapshot, to));
+        return match extracted {
+            Ok(name) => {
+                println!("Decrypted snapshot {} into {}", name, to.display());
+                true
+            }
+            Err(err) => {
+                println!("Failed to materialize snapshot: {}", err);
+                false
+            }
+        };
+    }
+    let layers = match task.snapshot_layers(snapshot) {
+        Ok(layers) => layers,
+        Err(err) => {
+            println!("{}", err);
+            return false;
+        }
+    };
+    if layers.len() > 1 {
+        println!("Layering {} snapshot directories", layers.len());
+    }
+    match restore::materialize(&layers, to) {
+        Ok(result) => {
+            println!(
+                "Linked {} and copied {} entries into {}",
+                result.linked,
+                result.copied,
+                to.display()
+            );
+            if result.linked > 0 {
+                println!("Linked files share their contents with the snapshots; copy them before editing.");
+            }
+            true
+        }
+        Err(err) => {
+            println!("Failed to materialize snapshot: {}", err);
+            false
+        }
+    }
+}
+
+fn export_snapshot(opt: &Options, id: &str, snapshot: &str, output: &Path) -> bool {
+    let task = match load_task(opt, id) {
+        Some(task) => task,
+        None => return false,
+    };
+    let layers = match task.snapshot_layers(snapshot) {
+        Ok(layers) => layers,
+        Err(err) => {
+            println!("{}", err);
+            return false;
+        }
+    };
+    let name = layers[0].file_name().unwrap().to_string_lossy().to_string();
+    println!("Exporting snapshot {} to {}", name, output.display());
+    match archive::export(&name, &layers, output) {
+        Ok(count) => {
+            println!("Exported {} entries", count);
+            true
+        }
+        Err(err) => {
+            println!("Export failed: {}", err);
+            false
+        }
+    }
+}
+
+fn import_snapshot(opt: &Options, id: &str, input: &Path) -> bool {
+    let task = match load_task(opt, id) {
+        Some(task) => task,
+        None => return false,
+    };
+    if task.is_update_task() {
+        println!("Task {} is an update task and has no snapshots.", id);
+        return false;
+    }
+    if task.is_encrypted() {
+        println!("Task {} is encrypted. Copy its encrypted snapshots into place instead.", id);
+        return false;
+    }
+    match archive::import(input, task.get_destination()) {
+        Ok(name) => {
+            println!("Imported snapshot {} into {}", name, task.get_destination().display());
+            true
+        }
+        Err(err) => {
+            println!("Import failed: {}", err);
+            false
+        }
+    }
+}
+
+#[cfg(feature = "fuse")]
+fn mount_snapshots(opt: &Options, id: &str, mountpoint: &Path) -> bool {
+    let task = match load_task(opt, id) {
+        Some(task) => task,
+        None => return false,
+    };
+    if task.is_update_task() {
+        println!("Task {} is an update task and has no snapshots.", id);
+        return false;
+    }
+    let mut snapshots = Vec::new();
+    for name in task.snapshots() {
+        match task.snapshot_layers(&name) {
+            Ok(layers) => snapshots.push((name, layers)),
+            Err(err) => {
+                println!("{}", err);
+                return false;
+            }
+        }
+    }
+    println!(
+        "Mounting {} snapshots at {}. Unmount with fusermount -u to exit.",
+        snapshots.len(),
+        mountpoint.display()
+    );
+    match fuse::mount(snapshots, mountpoint) {
+        Ok(_) => true,
+        Err(err) => {
+            println!("{}", err);
+            false
+        }
+    }
+}
+
+fn config_command(opt: &Options, cmd: &ConfigCommand) -> bool {
+    match cmd {
+        ConfigCommand::Path => match config::find_config(opt.config.as_deref()) {
+            Ok((path, source)) => {
+                println!("{} ({})", path.display(), source);
+                true
+            }
+            Err(err) => {
+                println!("{}", err);
+                false
+            }
+        },
+        ConfigCommand::Migrate => match config::migrate_legacy_config() {
+            Ok(path) => {
+                println!("Moved configuration file to {}", path.display());
+                for pattern in config::relative_includes(&path) {
+                    println!(
+                        "Warning: relative include {} is now resolved from {}",
+                        pattern,
+                        path.parent().map_or(String::new(), |p| p.display().to_string())
                     );
-                    if !get_yn(&prompt, true) {
-                        continue;
-                    }
-                }
-                if let Err(why) = task.run_task(opt.quiet, opt.debug, opt.dry_run) {
-                    let err = format!("Backup failed: {}", why);
-                    if operation_failed(&err, opt.quit_on_fail) {
-                        break;
-                    }
                 }
+                true
             }
-            Err(err) => match err.as_str() {
-                "EOF" => break,
-                _ => {
-                    println!("Failed to construct task: {}", err);
-                    return false;
+            Err(err) => {
+                println!("Migration failed: {}", err);
+                false
+            }
+        },
+        ConfigCommand::Convert { input, output } => match config::convert(input, output) {
+            Ok(comments) => {
+                println!("Wrote {}", output.display());
+                if comments > 0 {
+                    println!(
+                        "Warning: {} comment lines in {} were not carried over",
+                        comments,
+                        input.display()
+                    );
                 }
-            },
+                true
+            }
+            Err(err) => {
+                println!("Conversion failed: {}", err);
+                false
+            }
+        },
+    }
+}
+
+fn schedule_command(opt: &Options, cmd: &ScheduleCommand) -> bool {
+    let (ScheduleCommand::Install(target)
+    | ScheduleCommand::Uninstall(target)
+    | ScheduleCommand::Status(target)) = cmd;
+    let target = timers::Target {
+        backend: match (target.systemd, target.cron) {
+            (_, true) => timers::Backend::Cron,
+            _ => timers::Backend::Systemd,
+        },
+        dir: target.dir.clone(),
+    };
+    if let ScheduleCommand::Uninstall(_) = cmd {
+        return match timers::uninstall(&target) {
+            Ok(_) => {
+                println!("Removed scheduled jobs");
+                true
+            }
+            Err(err) => {
+                println!("Failed to remove scheduled jobs: {}", err);
+                false
+            }
+        };
+    }
+    let path = match config_path(opt) {
+        Some(path) => path,
+        None => return false,
+    };
+    let tasks = match config::load_tasks(&path, opt.debug) {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            println!("Configuration error: {}", err);
+            return false;
+        }
+    };
+    let mut jobs = Vec::new();
+    for task in &tasks {
+        if let Some(schedule) = task.get_schedule() {
+            if jobs.iter().any(|job: &timers::Job| job.id == task.get_id()) {
+                println!(
+                    "Scheduled tasks must have unique IDs, but '{}' is used more than once.",
+                    task.get_id()
+                );
+                return false;
+            }
+            jobs.push(timers::Job {
+                id: task.get_id(),
+                schedule,
+            });
+        }
+    }
+    let result = match cmd {
+        ScheduleCommand::Install(_) => timers::install(&target, &jobs, &path).map(|written| {
+            for file in written {
+                println!("Wrote {}", file.display());
+            }
+            println!("Installed {} scheduled jobs", jobs.len());
+        }),
+        _ => timers::status(&target, &jobs),
+    };
+    match result {
+        Ok(_) => true,
+        Err(err) => {
+            println!("{}", err);
+            false
         }
     }
-    println!("Backup complete.");
-    true
 }
 
 fn main() {
     let opt = Options::from_args();
-    process::exit(match run_backup(&opt) {
-        true => 1,
-        false => 0,
+    let success = match &opt.cmd {
+        None => run_backup(&opt, &[]),
+        Some(Command::Run { only }) => run_backup(&opt, only),
+        Some(Command::Config(cmd)) => config_command(&opt, cmd),
+        Some(Command::Check) => check_config(&opt),
+        Some(Command::Daemon) => run_daemon(&opt),
+        Some(Command::Schedule(cmd)) => schedule_command(&opt, cmd),
+        Some(Command::Verify {
+            task,
+            snapshot,
+            checksum,
+        }) => verify_task(&opt, task, snapshot, *checksum),
+        Some(Command::Scrub { task }) => scrub_snapshots(&opt, task.as_deref()),
+        Some(Command::Materialize { task, snapshot, to }) => {
+            materialize_snapshot(&opt, task, snapshot, to)
+        }
+        Some(Command::Export {
+            task,
+            snapshot,
+            output,
+        }) => export_snapshot(&opt, task, snapshot, output),
+        Some(Command::Import { task, input }) => import_snapshot(&opt, task, input),
+        Some(Command::Keygen { output }) => match crypto::generate_key_file(output) {
+            Ok(public) => {
+                println!("Key written to {}", output.display());
+                println!("Public key: {}", public);
+                println!("Keep a copy of the key file; snapshots can't be restored without it.");
+                true
+            }
+            Err(err) => {
+                println!("{}", err);
+                false
+            }
+        },
+        #[cfg(feature = "fuse")]
+        Some(Command::Mount { task, mountpoint }) => mount_snapshots(&opt, task, mountpoint),
+        Some(Command::Find { task, pattern }) => find_file(&opt, task, pattern),
+        Some(Command::Diff {
+            task,
+            from,
+            to,
+            prefix,
+            json,
+        }) => diff_snapshots(
+            &opt,
+            task,
+            from,
+            to.as_deref().unwrap_or("latest"),
+            prefix.as_deref(),
+            *json,
+        ),
+    };
+    process::exit(match success {
+        true => 0,
+        false => 1,
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Writes an rsync that only sleeps for a while, so that tasks started
+    /// together overlap, and returns its path
+    fn slow_rsync(dir: &Path) -> PathBuf {
+        let rsync = dir.join("rsync");
+        fs::write(&rsync, "#!/bin/sh\nsleep 1\n").unwrap();
+        fs::set_permissions(&rsync, fs::Permissions::from_mode(0o755)).unwrap();
+        rsync
+    }
+
+    #[test]
+    fn runs_tasks_without_ids_in_parallel() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["src", "a", "b"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        let config = dir.path().join("backup.conf");
+        let task = |dst: &str| {
+            format!(
+                "[UPDATE]\nSRC={}\nDST={}\n[END]\n",
+                dir.path().join("src").display(),
+                dir.path().join(dst).display()
+            )
+        };
+        fs::write(&config, task("a") + &task("b")).unwrap();
+        let tasks = config::load_tasks(&config, false).unwrap();
+        assert_eq!(tasks[0].get_id(), tasks[1].get_id());
+
+        let opt = Options::from_iter(["rsbackup", "--quiet", "--jobs", "2", "--no-wait"]);
+        let options = task::RunOptions {
+            rsync: slow_rsync(dir.path()),
+            ..run_options(&opt)
+        };
+        let outcomes: Vec<TaskOutcome> = thread::scope(|scope| {
+            let running: Vec<_> = tasks
+                .iter()
+                .map(|task| scope.spawn(|| run_parallel_task(&options, &config, task, false)))
+                .collect();
+            running.into_iter().map(|task| task.join().unwrap()).collect()
+        });
+        for outcome in outcomes {
+            match outcome {
+                TaskOutcome::Completed(_) => {}
+                TaskOutcome::Skipped(why) | TaskOutcome::Failed(why) => panic!("{}", why),
+            }
+        }
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Checksum manifests of snapshots. Each snapshot directory gets a manifest
+// file next to it with the same name and a .sha256 extension, listing every
+// regular file in the snapshot as
+//     <sha256>\t<size>\t<mtime>\t<inode>\t<path relative to the snapshot>
+// with backslashes, tabs and newlines in paths escaped.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+const HEADER: &str = "# rsbackup manifest: sha256, size, mtime, inode, path";
+const EXTENSION: &str = "sha256";
+
+pub struct ManifestEntry {
+    pub hash: String,
+    pub size: u64,
+    pub mtime: i64,
+    pub inode: u64,
+    pub path: String,
+}
+
+/// A file in a snapshot that doesn't match its manifest
+pub enum Problem {
+    Missing(String),
+    Modified(String),
+    Corrupted(String),
+    Unlisted(String),
+}
+
+impl Problem {
+    pub fn describe(&self) -> String {
+        match self {
+            Problem::Missing(path) => format!("Missing: {}", path),
+            Problem::Modified(path) => format!("Modified since the backup: {}", path),
+            Problem::Corrupted(path) => format!("Contents don't match the manifest: {}", path),
+            Problem::Unlisted(path) => format!("Not in the manifest: {}", path),
+        }
+    }
+}
+
+/// Hashes of files by device and inode, so that files hard-linked into
+/// several snapshots with --link-dest are only hashed once. Sizes and
+/// modification times are compared as well in case an inode was reused.
+#[derive(Default)]
+pub struct InodeCache {
+    hashes: HashMap<(u64, u64), (u64, i64, String)>,
+}
+
+impl InodeCache {
+    /// Creates a cache holding the hashes in the manifests of the snapshots
+    /// in the given directory. Manifests whose snapshot no longer exists are
+    /// ignored, as their inodes may have been reused. Manifests only list
+    /// inodes, so the files are taken to be on the device of their snapshot.
+    pub fn from_manifests(dir: &Path) -> Self {
+        let mut cache = InodeCache::default();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return cache,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let device = match fs::symlink_metadata(path.with_extension("")) {
+                Ok(metadata) if metadata.is_dir() => metadata.dev(),
+                _ => continue,
+            };
+            if let Ok(manifest) = load(&path) {
+                for entry in manifest {
+                    cache
+                        .hashes
+                        .insert((device, entry.inode), (entry.size, entry.mtime, entry.hash));
+                }
+            }
+        }
+        cache
+    }
+
+    /// Hashes a file unless the hash of its inode is already known
+    pub fn hash(&mut self, path: &Path, metadata: &Metadata) -> io::Result<String> {
+        let key = (metadata.dev(), metadata.ino());
+        if let Some((size, mtime, hash)) = self.hashes.get(&key) {
+            if *size == metadata.len() && *mtime == metadata.mtime() {
+                return Ok(hash.clone());
+            }
+        }
+        let hash = hash_file(path)?;
+        self.hashes.insert(key, (metadata.len(), metadata.mtime(), hash.clone()));
+        Ok(hash)
+    }
+}
+
+/// Path of the manifest of a snapshot, which is kept outside the snapshot so
+/// that it doesn't become part of the backed up files
+pub fn manifest_path(snapshot: &Path) -> PathBuf {
+    snapshot.with_extension(EXTENSION)
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn escape(path: &str) -> String {
+    path.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(path: &str) -> String {
+    let mut result = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Lists the regular files in a directory tree with their paths relative to
+/// the root, sorted by path
+pub fn walk(root: &Path) -> Result<Vec<(String, PathBuf, Metadata)>, String> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = fs::read_dir(&dir)
+            .map_err(|why| format!("Failed to read {}: {}", dir.display(), why))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(why) => return Err(format!("Failed to read {}: {}", path.display(), why)),
+            };
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if metadata.is_file() {
+                let relative = path.strip_prefix(root).unwrap().to_string_lossy().to_string();
+                files.push((relative, path, metadata));
+            }
+        }
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(files)
+}
+
+/// Hashes every file in a snapshot and writes its manifest. Returns the
+/// number of files listed.
+pub fn write(snapshot: &Path, cache: &mut InodeCache) -> Result<usize, String> {
+    let mut contents = String::from(HEADER);
+    contents.push('\n');
+    let files = walk(snapshot)?;
+    for (relative, path, metadata) in &files {
+        let hash = cache
+            .hash(path, metadata)
+            .map_err(|why| format!("Failed to read {}: {}", path.display(), why))?;
+        contents.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            hash,
+            metadata.len(),
+            metadata.mtime(),
+            metadata.ino(),
+            escape(relative)
+        ));
+    }
+    let path = manifest_path(snapshot);
+    let temporary = path.with_extension("tmp");
+    let written = File::create(&temporary)
+        .and_then(|mut file| file.write_all(contents.as_bytes()).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temporary, &path));
+    match written {
+        Ok(_) => Ok(files.len()),
+        Err(why) => Err(format!("Failed to write {}: {}", path.display(), why)),
+    }
+}
+
+/// Reads a manifest file
+pub fn load(path: &Path) -> Result<Vec<ManifestEntry>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|why| format!("Failed to read {}: {}", path.display(), why))?;
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        let entry = match fields[..] {
+            [hash, size, mtime, inode, path] => match (size.parse(), mtime.parse(), inode.parse()) {
+                (Ok(size), Ok(mtime), Ok(inode)) => Some(ManifestEntry {
+                    hash: hash.to_string(),
+                    size,
+                    mtime,
+                    inode,
+                    path: unescape(path),
+                }),
+                _ => None,
+            },
+            _ => None,
+        };
+        match entry {
+            Some(entry) => entries.push(entry),
+            None => {
+                return Err(format!(
+                    "{}: Line {}: Invalid manifest entry",
+                    path.display(),
+                    number + 1
+                ));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Hashes the files in a snapshot again and compares them with its manifest
+pub fn scrub(snapshot: &Path, cache: &mut InodeCache) -> Result<Vec<Problem>, String> {
+    let manifest = load(&manifest_path(snapshot))?;
+    let mut problems = Vec::new();
+    for entry in &manifest {
+        let path = snapshot.join(&entry.path);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                problems.push(Problem::Missing(entry.path.clone()));
+                continue;
+            }
+        };
+        if metadata.len() != entry.size || metadata.mtime() != entry.mtime {
+            problems.push(Problem::Modified(entry.path.clone()));
+            continue;
+        }
+        match cache.hash(&path, &metadata) {
+            Ok(hash) if hash == entry.hash => {}
+            Ok(_) => problems.push(Problem::Corrupted(entry.path.clone())),
+            Err(why) => return Err(format!("Failed to read {}: {}", path.display(), why)),
+        }
+    }
+    let listed: HashSet<&str> = manifest.iter().map(|entry| entry.path.as_str()).collect();
+    for (relative, _, _) in walk(snapshot)? {
+        if !listed.contains(relative.as_str()) {
+            problems.push(Problem::Unlisted(relative));
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::write as write_file;
+
+    const OLD: &str = "2024-01-01--00_00";
+    const NEW: &str = "2024-01-02--00_00";
+    const HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    /// Replaces the hashes in a manifest, so that tests can tell whether a
+    /// hash was read from it or computed again
+    fn forge_hashes(snapshot: &Path) {
+        let path = manifest_path(snapshot);
+        let forged: String = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| match line.split_once('\t') {
+                Some((_, rest)) => format!("{}\t{}\n", "0".repeat(64), rest),
+                None => format!("{}\n", line),
+            })
+            .collect();
+        fs::write(path, forged).unwrap();
+    }
+
+    #[test]
+    fn escaped_paths_can_be_unescaped() {
+        for path in ["plain.txt", "tab\there", "new\nline", "back\\slash\\t", "end\\"] {
+            let escaped = escape(path);
+            assert!(!escaped.contains('\t') && !escaped.contains('\n'));
+            assert_eq!(unescape(&escaped), path);
+        }
+    }
+
+    #[test]
+    fn lists_every_file_in_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join(OLD);
+        write_file(&snapshot, "hello.txt", "hello");
+        write_file(&snapshot, "dir/tab\tname", "hello");
+        assert_eq!(write(&snapshot, &mut InodeCache::default()), Ok(2));
+
+        let manifest = load(&manifest_path(&snapshot)).unwrap();
+        let paths: Vec<&str> = manifest.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["dir/tab\tname", "hello.txt"]);
+        let metadata = fs::metadata(snapshot.join("hello.txt")).unwrap();
+        let entry = &manifest[1];
+        assert_eq!(entry.hash, HELLO);
+        assert_eq!((entry.size, entry.inode), (5, metadata.ino()));
+        assert_eq!(entry.mtime, metadata.mtime());
+    }
+
+    #[test]
+    fn scrubbing_finds_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join(OLD);
+        for name in ["same", "missing", "modified", "corrupted"] {
+            write_file(&snapshot, name, "hello");
+        }
+        write(&snapshot, &mut InodeCache::default()).unwrap();
+
+        fs::remove_file(snapshot.join("missing")).unwrap();
+        write_file(&snapshot, "modified", "changed");
+        write_file(&snapshot, "unlisted", "hello");
+        // Same size and modification time, different contents
+        let corrupted = snapshot.join("corrupted");
+        let modified = fs::metadata(&corrupted).unwrap().modified().unwrap();
+        fs::write(&corrupted, "HELLO").unwrap();
+        File::options().write(true).open(&corrupted).unwrap().set_modified(modified).unwrap();
+
+        let problems: Vec<String> = scrub(&snapshot, &mut InodeCache::default())
+            .unwrap()
+            .iter()
+            .map(Problem::describe)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "Contents don't match the manifest: corrupted",
+                "Missing: missing",
+                "Modified since the backup: modified",
+                "Not in the manifest: unlisted",
+            ]
+        );
+    }
+
+    #[test]
+    fn reuses_hashes_of_linked_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join(OLD), dir.path().join(NEW));
+        write_file(&old, "hello.txt", "hello");
+        write(&old, &mut InodeCache::default()).unwrap();
+        forge_hashes(&old);
+        fs::create_dir(&new).unwrap();
+        fs::hard_link(old.join("hello.txt"), new.join("hello.txt")).unwrap();
+
+        write(&new, &mut InodeCache::from_manifests(dir.path())).unwrap();
+        assert_eq!(load(&manifest_path(&new)).unwrap()[0].hash, "0".repeat(64));
+    }
+
+    #[test]
+    fn hashes_files_again_after_they_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join(OLD);
+        write_file(&snapshot, "hello.txt", "hi");
+        let path = snapshot.join("hello.txt");
+        let mut cache = InodeCache::default();
+        let before = cache.hash(&path, &fs::metadata(&path).unwrap()).unwrap();
+        fs::write(&path, "hello").unwrap();
+        let after = cache.hash(&path, &fs::metadata(&path).unwrap()).unwrap();
+        assert_ne!(before, after);
+        assert_eq!(after, HELLO);
+    }
+
+    #[test]
+    fn ignores_manifests_of_removed_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join(OLD), dir.path().join(NEW));
+        write_file(&old, "hello.txt", "hello");
+        write(&old, &mut InodeCache::default()).unwrap();
+        forge_hashes(&old);
+        fs::create_dir(&new).unwrap();
+        fs::hard_link(old.join("hello.txt"), new.join("hello.txt")).unwrap();
+        fs::remove_dir_all(&old).unwrap();
+
+        write(&new, &mut InodeCache::from_manifests(dir.path())).unwrap();
+        assert_eq!(load(&manifest_path(&new)).unwrap()[0].hash, HELLO);
+    }
+}
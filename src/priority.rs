@@ -0,0 +1,167 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Bandwidth limits and CPU and IO scheduling priorities for the rsync
+// processes started by tasks. The priorities don't apply to the archives of
+// encrypted tasks, which rsbackup compresses and encrypts itself.
+
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::str::FromStr;
+
+// See ioprio_set(2)
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// IO scheduling class and priority level, as set by ionice(1)
+#[derive(Clone, Copy, Debug)]
+pub enum IoPriority {
+    Realtime(u32),
+    BestEffort(u32),
+    Idle,
+}
+
+impl IoPriority {
+    fn to_ioprio(self) -> libc::c_int {
+        let (class, level) = match self {
+            IoPriority::Realtime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        ((class << IOPRIO_CLASS_SHIFT) | level) as libc::c_int
+    }
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    /// Parses `idle`, `best-effort` or `realtime`, the latter two optionally
+    /// followed by a level from 0 (highest) to 7 (lowest), e.g.
+    /// `best-effort:7`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match value.split_once(':') {
+            Some((class, level)) => match level.parse::<u32>() {
+                Ok(level) if level <= 7 => (class, level),
+                _ => return Err(format!("Invalid IO priority level '{}'. Expected 0-7.", level)),
+            },
+            None => (value, 4),
+        };
+        match class {
+            "realtime" => Ok(IoPriority::Realtime(level)),
+            "best-effort" => Ok(IoPriority::BestEffort(level)),
+            "idle" if !value.contains(':') => Ok(IoPriority::Idle),
+            _ => Err(format!(
+                "Invalid IO priority '{}'. Expected 'idle', 'best-effort[:level]' or 'realtime[:level]'.",
+                value
+            )),
+        }
+    }
+}
+
+/// Parses a niceness from -20 (highest priority) to 19 (lowest)
+pub fn parse_nice(value: &str) -> Result<i32, String> {
+    match value.parse() {
+        Ok(nice) if (-20..=19).contains(&nice) => Ok(nice),
+        _ => Err(format!("Invalid niceness '{}'. Expected -20 to 19.", value)),
+    }
+}
+
+fn is_number(digits: &str) -> bool {
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Checks that a bandwidth limit is in a form rsync's --bwlimit accepts: a
+/// rate in KiB per second or a number with a K, M or G suffix, e.g. 500 or
+/// 1.5M. 0 means no limit.
+pub fn parse_bwlimit(value: &str) -> Result<String, String> {
+    let number = value.strip_suffix(['K', 'M', 'G', 'k', 'm', 'g']).unwrap_or(value);
+    let valid = match number.split_once('.') {
+        Some((whole, fraction)) => [whole, fraction].iter().all(|part| is_number(part)),
+        None => is_number(number),
+    };
+    match valid {
+        true => Ok(value.to_string()),
+        false => Err(format!(
+            "Invalid bandwidth limit '{}'. Expected a rate such as 500K or 2M.",
+            value
+        )),
+    }
+}
+
+/// Makes a command run with the given niceness and IO priority. Lowering the
+/// niceness below 0 or using the realtime IO class requires root, so the
+/// command fails to start otherwise.
+pub fn apply(command: &mut Command, nice: Option<i32>, ionice: Option<IoPriority>) {
+    if nice.is_none() && ionice.is_none() {
+        return;
+    }
+    // Only async-signal-safe system calls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(ionice) = ionice {
+                let ioprio = ionice.to_ioprio();
+                if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_io_priorities() {
+        let valid = [
+            ("idle", 3 << IOPRIO_CLASS_SHIFT),
+            ("best-effort", (2 << IOPRIO_CLASS_SHIFT) | 4),
+            ("best-effort:0", 2 << IOPRIO_CLASS_SHIFT),
+            ("realtime:7", (1 << IOPRIO_CLASS_SHIFT) | 7),
+        ];
+        for (value, ioprio) in valid {
+            let priority: IoPriority = value.parse().unwrap();
+            assert_eq!(priority.to_ioprio(), ioprio as libc::c_int, "{}", value);
+        }
+        for value in ["idle:3", "best-effort:8", "realtime:-1", "realtime:", "low"] {
+            assert!(value.parse::<IoPriority>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parses_niceness() {
+        assert_eq!(parse_nice("-20"), Ok(-20));
+        assert_eq!(parse_nice("19"), Ok(19));
+        assert!(parse_nice("20").is_err());
+        assert!(parse_nice("low").is_err());
+    }
+
+    #[test]
+    fn parses_bandwidth_limits() {
+        for value in ["0", "500", "500K", "1.5M", "2g"] {
+            assert_eq!(parse_bwlimit(value), Ok(value.to_string()));
+        }
+        for value in ["", "M", "1.M", ".5M", "1.5.5M", "1.5T", "-1", "1 M"] {
+            assert!(parse_bwlimit(value).is_err(), "{}", value);
+        }
+    }
+}
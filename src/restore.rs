@@ -0,0 +1,226 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Reconstruction of complete snapshots from layered snapshot directories.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Number of entries placed in a materialized tree
+#[derive(Default)]
+pub struct Materialized {
+    pub linked: usize,
+    pub copied: usize,
+}
+
+/// Gives a file or directory the permissions and modification time of
+/// another
+fn copy_attributes(metadata: &Metadata, to: &Path) -> Result<(), String> {
+    File::open(to)
+        .and_then(|file| file.set_modified(metadata.modified()?))
+        .and_then(|_| fs::set_permissions(to, metadata.permissions()))
+        .map_err(|why| format!("Failed to set attributes of {}: {}", to.display(), why))
+}
+
+/// Creates a FIFO, socket or device node like the given one
+fn make_node(metadata: &Metadata, to: &Path) -> Result<(), String> {
+    let path = CString::new(to.as_os_str().as_bytes())
+        .map_err(|why| format!("Invalid path {}: {}", to.display(), why))?;
+    if unsafe { libc::mknod(path.as_ptr(), metadata.mode(), metadata.rdev()) } != 0 {
+        let why = std::io::Error::last_os_error();
+        return Err(format!("Failed to create {}: {}", to.display(), why));
+    }
+    Ok(())
+}
+
+/// Copies an entry that isn't a directory. Regular files keep their
+/// permissions and modification time, symbolic links are recreated with
+/// the same target and other entries are recreated as the same kind of
+/// node.
+fn copy_entry(from: &Path, metadata: &Metadata, to: &Path) -> Result<(), String> {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        let link = fs::read_link(from)
+            .map_err(|why| format!("Failed to read {}: {}", from.display(), why))?;
+        symlink(&link, to).map_err(|why| format!("Failed to create {}: {}", to.display(), why))
+    } else if file_type.is_file() {
+        fs::copy(from, to).map_err(|why| {
+            format!("Failed to copy {} to {}: {}", from.display(), to.display(), why)
+        })?;
+        copy_attributes(metadata, to)
+    } else {
+        make_node(metadata, to)?;
+        copy_attributes(metadata, to)
+    }
+}
+
+/// Fills a directory with the merge of the given directories, newest first.
+/// Each entry is taken from the newest directory containing it. Entries
+/// below a directory only come from the layers where it is a directory and
+/// no newer layer has a file in its place.
+fn merge(dirs: &[PathBuf], target: &Path, result: &mut Materialized) -> Result<(), String> {
+    let mut entries = BTreeMap::new();
+    for dir in dirs {
+        let listing = fs::read_dir(dir)
+            .map_err(|why| format!("Failed to read {}: {}", dir.display(), why))?;
+        for entry in listing.flatten() {
+            entries.entry(entry.file_name()).or_insert_with(|| entry.path());
+        }
+    }
+    for (file_name, path) in entries {
+        let destination = target.join(&file_name);
+        let metadata = fs::symlink_metadata(&path)
+            .map_err(|why| format!("Failed to read {}: {}", path.display(), why))?;
+        if metadata.is_dir() {
+            fs::create_dir(&destination)
+                .map_err(|why| format!("Failed to create {}: {}", destination.display(), why))?;
+            let children: Vec<PathBuf> = dirs
+                .iter()
+                .map(|dir| dir.join(&file_name))
+                .take_while(|child| match fs::symlink_metadata(child) {
+                    Ok(metadata) => metadata.is_dir(),
+                    Err(_) => true,
+                })
+                .filter(|child| child.is_dir())
+                .collect();
+            merge(&children, &destination, result)?;
+            // Only once the directory is filled, as it may be read-only
+            copy_attributes(&metadata, &destination)?;
+            result.copied += 1;
+        } else if fs::hard_link(&path, &destination).is_ok() {
+            result.linked += 1;
+        } else {
+            copy_entry(&path, &metadata, &destination)?;
+            result.copied += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Builds a complete tree in `target` from the given layers, newest first,
+/// merged the same way as for the diff, mount and export commands. Files,
+/// symbolic links and other nodes are hard-linked into the target if
+/// possible, or copied otherwise. Directories are created with the
+/// permissions and modification time they have in the newest layer. The
+/// target must not exist or be empty.
+pub fn materialize(layers: &[impl AsRef<Path>], target: &Path) -> Result<Materialized, String> {
+    if let Ok(mut entries) = fs::read_dir(target) {
+        if entries.next().is_some() {
+            return Err(format!("{} is not empty.", target.display()));
+        }
+    }
+    fs::create_dir_all(target)
+        .map_err(|why| format!("Failed to create {}: {}", target.display(), why))?;
+    let layers: Vec<PathBuf> = layers.iter().map(|layer| layer.as_ref().to_path_buf()).collect();
+    let mut result = Materialized::default();
+    merge(&layers, target, &mut result)?;
+    if let Some(newest) = layers.first() {
+        let metadata = fs::metadata(newest)
+            .map_err(|why| format!("Failed to read {}: {}", newest.display(), why))?;
+        copy_attributes(&metadata, target)?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{assert_merged, write, write_layers};
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn newer_layers_hide_older_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        write_layers(&new, &old);
+        let target = dir.path().join("target");
+        let result = materialize(&[new, old], &target).unwrap();
+        assert_merged(&target);
+        assert!(!target.join("became_file/child.txt").exists());
+        // Four files and one directory
+        assert_eq!(result.linked + result.copied, 5);
+    }
+
+    #[test]
+    fn keeps_links_nodes_and_empty_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = dir.path().join("layer");
+        write(&layer, "file.txt", "contents");
+        symlink("file.txt", layer.join("link")).unwrap();
+        symlink("/nonexistent", layer.join("dangling")).unwrap();
+        fs::create_dir(layer.join("empty")).unwrap();
+        let fifo = CString::new(layer.join("pipe").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        let target = dir.path().join("target");
+        materialize(&[&layer], &target).unwrap();
+        assert_eq!(fs::read_link(target.join("link")).unwrap(), Path::new("file.txt"));
+        assert_eq!(fs::read_link(target.join("dangling")).unwrap(), Path::new("/nonexistent"));
+        assert_eq!(fs::read_dir(target.join("empty")).unwrap().count(), 0);
+        let pipe = fs::symlink_metadata(target.join("pipe")).unwrap();
+        assert!(pipe.file_type().is_fifo());
+    }
+
+    #[test]
+    fn directories_keep_their_attributes() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = dir.path().join("layer");
+        write(&layer, "private/file.txt", "contents");
+        let private = layer.join("private");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        File::open(&private).unwrap().set_modified(modified).unwrap();
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o700)).unwrap();
+
+        let target = dir.path().join("target");
+        materialize(&[&layer], &target).unwrap();
+        let metadata = fs::metadata(target.join("private")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(fs::read_to_string(target.join("private/file.txt")).unwrap(), "contents");
+    }
+
+    #[test]
+    fn copies_entries_that_cannot_be_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = dir.path().join("layer");
+        write(&layer, "file.txt", "contents");
+        symlink("file.txt", layer.join("link")).unwrap();
+        let target = dir.path().join("target");
+        fs::create_dir(&target).unwrap();
+        let metadata = |path: &Path| fs::symlink_metadata(path).unwrap();
+        for name in ["file.txt", "link"] {
+            copy_entry(&layer.join(name), &metadata(&layer.join(name)), &target.join(name))
+                .unwrap();
+        }
+        let inode = |root: &Path| metadata(&root.join("file.txt")).ino();
+        assert_ne!(inode(&target), inode(&layer));
+        assert_eq!(fs::read_to_string(target.join("file.txt")).unwrap(), "contents");
+        assert_eq!(fs::read_link(target.join("link")).unwrap(), Path::new("file.txt"));
+    }
+
+    #[test]
+    fn refuses_non_empty_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = dir.path().join("layer");
+        write(&layer, "file.txt", "contents");
+        let target = dir.path().join("target");
+        write(&target, "existing.txt", "existing");
+        assert!(materialize(&[&layer], &target).is_err());
+    }
+}
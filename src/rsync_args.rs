@@ -0,0 +1,114 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Checks of the options passed through to rsync with RSYNC_ARGS= and
+// --rsync-arg. Like the format module, this is shared by the command line
+// utility and the editor.
+
+/// rsync options that conflict with the arguments constructed for each task
+const DENIED_RSYNC_ARGS: [&str; 10] = [
+    "--dry-run",
+    "--list-only",
+    "--files-from",
+    "--exclude-from",
+    "--include-from",
+    "--link-dest",
+    "--compare-dest",
+    "--copy-dest",
+    "--remove-source-files",
+    "--server",
+];
+
+/// Checks that an argument can be passed through to rsync. Only options are
+/// allowed; the paths are always set by the task. Options that take a value
+/// must be written as `--option=value`.
+pub fn check_rsync_arg(arg: &str) -> Result<(), String> {
+    if let Some(option) = arg.strip_prefix("--") {
+        let name = format!("--{}", option.split('=').next().unwrap_or(""));
+        if DENIED_RSYNC_ARGS.contains(&name.as_str()) {
+            return Err(format!("rsync option {} can't be set manually.", name));
+        }
+        Ok(())
+    } else if let Some(flags) = arg.strip_prefix('-') {
+        if flags.is_empty() {
+            return Err(String::from("Empty rsync option."));
+        }
+        if flags.chars().all(|c| c.is_ascii_alphabetic()) && flags.contains('n') {
+            return Err(String::from(
+                "rsync option -n can't be set manually. Use --dry-run instead.",
+            ));
+        }
+        Ok(())
+    } else {
+        Err(format!(
+            "Unexpected rsync argument '{}'. Only options are allowed, with values given as \
+             --option=value.",
+            arg
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_options() {
+        let args = [
+            "--checksum",
+            "--bwlimit=1M",
+            "-z",
+            "-avz",
+            "-B1024",
+            "--delete",
+            "--delete-after",
+        ];
+        for arg in args {
+            assert_eq!(check_rsync_arg(arg), Ok(()), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn rejects_options_set_by_the_task() {
+        let args = [
+            "--dry-run",
+            "--dry-run=yes",
+            "--files-from=list",
+            "--link-dest=/old",
+            "--server",
+        ];
+        for arg in args {
+            let name = arg.split('=').next().unwrap();
+            let err = format!("rsync option {} can't be set manually.", name);
+            assert_eq!(check_rsync_arg(arg), Err(err));
+        }
+    }
+
+    #[test]
+    fn rejects_dry_runs_in_bundled_flags() {
+        for arg in ["-n", "-avn", "-nav"] {
+            let err = check_rsync_arg(arg).unwrap_err();
+            assert!(err.starts_with("rsync option -n can't be set manually."), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn rejects_positional_arguments() {
+        for arg in ["/home/user", "remote:/backup", ""] {
+            let err = check_rsync_arg(arg).unwrap_err();
+            assert!(err.starts_with("Unexpected rsync argument"), "{}", arg);
+        }
+        assert_eq!(check_rsync_arg("-"), Err(String::from("Empty rsync option.")));
+    }
+}
@@ -0,0 +1,600 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Timelike};
+
+use crate::lock;
+
+/// When a task should be run, given either as a cron expression with five
+/// fields (minute, hour, day of month, month, day of week) or an interval
+/// such as `90s`, `30m`, `6h`, `1d` or `every 2w`
+#[derive(Clone)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(CronExpression),
+}
+
+#[derive(Clone)]
+pub struct CronExpression {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Parses one field of a cron expression, which is a comma separated list of
+/// `*`, single values and ranges, each with an optional `/step`
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("Invalid step in '{}'.", part)),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            match (start.parse(), end.parse()) {
+                (Ok(start), Ok(end)) => (start, end),
+                _ => return Err(format!("Invalid range '{}'.", range)),
+            }
+        } else {
+            match range.parse() {
+                // A single value with a step runs from that value to the end
+                Ok(value) if part.contains('/') => (value, max),
+                Ok(value) => (value, value),
+                Err(_) => return Err(format!("Invalid value '{}'.", range)),
+            }
+        };
+        if start < min || end > max || start > end {
+            return Err(format!(
+                "'{}' is outside the range {}-{}.",
+                range, min, max
+            ));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+/// Lists the allowed values of a field, joining consecutive values into ranges
+/// written with the given separator. Returns `*` if every value is allowed.
+fn format_field(allowed: &[bool], min: usize, max: usize, range_sep: &str) -> String {
+    if allowed[min..=max].iter().all(|a| *a) {
+        return String::from("*");
+    }
+    let mut parts = Vec::new();
+    let mut value = min;
+    while value <= max {
+        if !allowed[value] {
+            value += 1;
+            continue;
+        }
+        let start = value;
+        while value < max && allowed[value + 1] {
+            value += 1;
+        }
+        parts.push(match value - start {
+            0 => format!("{}", start),
+            1 => format!("{},{}", start, value),
+            _ => format!("{}{}{}", start, range_sep, value),
+        });
+        value += 1;
+    }
+    parts.join(",")
+}
+
+impl CronExpression {
+    fn to_cron(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            format_field(&self.minutes, 0, 59, "-"),
+            format_field(&self.hours, 0, 23, "-"),
+            if self.any_day { String::from("*") } else { format_field(&self.days, 1, 31, "-") },
+            format_field(&self.months, 1, 12, "-"),
+            if self.any_weekday { String::from("*") } else { format_field(&self.weekdays, 0, 6, "-") }
+        )
+    }
+
+    /// Converts the expression to systemd calendar events. Cron runs a task if
+    /// either the day of the month or the day of the week matches when both
+    /// are restricted, which takes two events in systemd.
+    fn to_systemd(&self) -> Vec<String> {
+        const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        let weekdays: Vec<&str> = (0..7)
+            .filter(|day| self.weekdays[*day])
+            .map(|day| WEEKDAYS[day])
+            .collect();
+        let time = format!(
+            "{}:{}:00",
+            format_field(&self.hours, 0, 23, ".."),
+            format_field(&self.minutes, 0, 59, "..")
+        );
+        let months = format_field(&self.months, 1, 12, "..");
+        let days = format_field(&self.days, 1, 31, "..");
+        let by_day = format!("*-{}-{} {}", months, days, time);
+        let by_weekday = format!("{} *-{}-* {}", weekdays.join(","), months, time);
+        match (self.any_day, self.any_weekday) {
+            (true, true) => vec![format!("*-{}-* {}", months, time)],
+            (false, true) => vec![by_day],
+            (true, false) => vec![by_weekday],
+            (false, false) => vec![by_day, by_weekday],
+        }
+    }
+
+    fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Expected 5 fields in cron expression '{}'.",
+                expression
+            ));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        Ok(CronExpression {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+        // As in cron, a day matches if either field matches when both are
+        // restricted
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        }
+    }
+
+    fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut date = time.date_naive();
+        let mut hour = time.hour();
+        let mut minute = time.minute() + 1;
+        // Expressions that never match, such as February 31st, give up after
+        // a few years
+        let limit = date + Duration::days(366 * 5);
+        while date < limit {
+            if !self.months[date.month() as usize] || !self.matches_day(date) {
+                date = date.succ_opt()?;
+                hour = 0;
+                minute = 0;
+                continue;
+            }
+            while hour < 24 {
+                if self.hours[hour as usize] {
+                    while minute < 60 {
+                        if self.minutes[minute as usize] {
+                            let naive = date.and_hms_opt(hour, minute, 0)?;
+                            // Times skipped by daylight saving changes don't
+                            // exist in local time
+                            if let Some(next) = Local.from_local_datetime(&naive).earliest() {
+                                if next > time {
+                                    return Some(next);
+                                }
+                            }
+                        }
+                        minute += 1;
+                    }
+                }
+                hour += 1;
+                minute = 0;
+            }
+            date = date.succ_opt()?;
+            hour = 0;
+            minute = 0;
+        }
+        None
+    }
+}
+
+/// Parses a duration given as a number of seconds, minutes, hours, days or
+/// weeks, e.g. 90s, 30m or 6h. Used for intervals between scheduled runs as
+/// well as timeouts and retry delays.
+pub fn parse_duration(value: &str) -> Option<std::time::Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (count, unit) = value.split_at(split);
+    let count: u64 = count.parse().ok().filter(|count| *count > 0)?;
+    let seconds = match unit {
+        "s" => count,
+        "m" => count.checked_mul(60)?,
+        "h" => count.checked_mul(60 * 60)?,
+        "d" => count.checked_mul(24 * 60 * 60)?,
+        "w" => count.checked_mul(7 * 24 * 60 * 60)?,
+        _ => return None,
+    };
+    Some(std::time::Duration::from_secs(seconds))
+}
+
+impl Schedule {
+    pub fn parse(schedule: &str) -> Result<Self, String> {
+        let schedule = schedule.trim();
+        let expression = match schedule {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" => "0 0 1 1 *",
+            _ => {
+                let interval = schedule.strip_prefix("every ").unwrap_or(schedule);
+                let duration = parse_duration(interval).and_then(|d| Duration::from_std(d).ok());
+                if let Some(duration) = duration {
+                    return Ok(Schedule::Interval(duration));
+                }
+                schedule
+            }
+        };
+        match CronExpression::parse(expression) {
+            Ok(cron) => Ok(Schedule::Cron(cron)),
+            Err(err) => Err(format!("Invalid schedule '{}': {}", schedule, err)),
+        }
+    }
+
+    /// Converts the schedule to the lines of the `[Timer]` section of a
+    /// systemd timer unit
+    pub fn to_systemd_timer(&self) -> Vec<String> {
+        match self {
+            Schedule::Interval(interval) => vec![
+                String::from("OnBootSec=5min"),
+                format!("OnUnitActiveSec={}s", interval.num_seconds()),
+            ],
+            Schedule::Cron(cron) => {
+                let mut lines: Vec<String> = cron
+                    .to_systemd()
+                    .into_iter()
+                    .map(|event| format!("OnCalendar={}", event))
+                    .collect();
+                // Run once at startup if a run was missed while powered off
+                lines.push(String::from("Persistent=true"));
+                lines
+            }
+        }
+    }
+
+    /// Converts the schedule to the time fields of a crontab entry. Intervals
+    /// can only be converted if they evenly divide an hour or a day, or are
+    /// exactly one week.
+    pub fn to_cron(&self) -> Result<String, String> {
+        match self {
+            Schedule::Cron(cron) => Ok(cron.to_cron()),
+            Schedule::Interval(interval) if interval.num_seconds() % 60 != 0 => Err(format!(
+                "An interval of {} seconds can't be expressed as a cron schedule.",
+                interval.num_seconds()
+            )),
+            Schedule::Interval(interval) => {
+                let minutes = interval.num_minutes();
+                match minutes {
+                    m if m < 60 && 60 % m == 0 => Ok(format!("*/{} * * * *", m)),
+                    60 => Ok(String::from("0 * * * *")),
+                    m if m % 60 == 0 && m < 24 * 60 && 24 * 60 % m == 0 => {
+                        Ok(format!("0 */{} * * *", m / 60))
+                    }
+                    m if m == 24 * 60 => Ok(String::from("0 0 * * *")),
+                    m if m == 7 * 24 * 60 => Ok(String::from("0 0 * * 0")),
+                    _ => Err(format!(
+                        "An interval of {} minutes can't be expressed as a cron schedule.",
+                        minutes
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Determines the first time the task should run after the given time
+    pub fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::Interval(interval) => Some(time + *interval),
+            Schedule::Cron(cron) => cron.next_after(time),
+        }
+    }
+}
+
+/// Recurring hours of the week, given as the hour and day of week fields of a
+/// cron expression, e.g. `9-17 1-5` for 9:00 to 17:59 on weekdays
+#[derive(Clone)]
+pub struct TimeWindow {
+    hours: Vec<bool>,
+    weekdays: Vec<bool>,
+}
+
+impl TimeWindow {
+    pub fn parse(window: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = window.split_whitespace().collect();
+        if fields.len() != 2 {
+            return Err(format!(
+                "Invalid time window '{}': expected hours and days of the week, e.g. '9-17 1-5'.",
+                window
+            ));
+        }
+        let error = |err| format!("Invalid time window '{}': {}", window, err);
+        let hours = parse_field(fields[0], 0, 23).map_err(error)?;
+        let mut weekdays = parse_field(fields[1], 0, 7).map_err(error)?;
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        Ok(TimeWindow { hours, weekdays })
+    }
+
+    pub fn contains(&self, time: DateTime<Local>) -> bool {
+        self.hours[time.hour() as usize]
+            && self.weekdays[time.weekday().num_days_from_sunday() as usize]
+    }
+}
+
+/// Times at which each scheduled task was last started, kept on disk so that
+/// runs missed while the daemon wasn't running can be caught up on. Each
+/// configuration file has its own state file, as task IDs are only unique
+/// within one.
+pub struct LastRuns {
+    path: Option<PathBuf>,
+    runs: HashMap<String, DateTime<Local>>,
+}
+
+/// Directory for rsbackup's runtime state, following the XDG base directory
+/// specification
+pub fn state_dir() -> Option<PathBuf> {
+    let state_home = match env::var_os("XDG_STATE_HOME").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path,
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local").join("state"),
+    };
+    Some(state_home.join("rsbackup"))
+}
+
+/// State file holding the last run times of the tasks in a configuration
+fn last_runs_path(state_dir: &Path, config: &Path) -> PathBuf {
+    state_dir.join(format!("last-run-{}", lock::path_key(config)))
+}
+
+impl LastRuns {
+    /// Loads the last run times of the tasks in a configuration file
+    pub fn load(config: &Path) -> Self {
+        Self::open(state_dir().map(|dir| last_runs_path(&dir, config)))
+    }
+
+    /// Loads last run times from a state file, if there is one
+    pub fn open(path: Option<PathBuf>) -> Self {
+        let mut runs = HashMap::new();
+        if let Some(contents) = path.as_ref().and_then(|p| fs::read_to_string(p).ok()) {
+            for line in contents.lines() {
+                if let Some((time, id)) = line.split_once('\t') {
+                    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+                        runs.insert(id.to_string(), time.with_timezone(&Local));
+                    }
+                }
+            }
+        }
+        LastRuns { path, runs }
+    }
+
+    pub fn get(&self, id: &str) -> Option<DateTime<Local>> {
+        self.runs.get(id).copied()
+    }
+
+    pub fn record(&mut self, id: &str, time: DateTime<Local>) -> Result<(), String> {
+        self.runs.insert(id.to_string(), time);
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Err(String::from("Could not determine state directory.")),
+        };
+        if let Some(parent) = path.parent() {
+            if let Err(why) = fs::create_dir_all(parent) {
+                return Err(format!("Failed to create {}: {}", parent.display(), why));
+            }
+        }
+        let contents: String = self
+            .runs
+            .iter()
+            .map(|(id, time)| format!("{}\t{}\n", time.to_rfc3339(), id))
+            .collect();
+        fs::write(path, contents).map_err(|why| format!("Failed to write {}: {}", path.display(), why))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn allowed(values: &[bool]) -> Vec<usize> {
+        (0..values.len()).filter(|value| values[*value]).collect()
+    }
+
+    fn cron(expression: &str) -> CronExpression {
+        match Schedule::parse(expression).unwrap() {
+            Schedule::Cron(cron) => cron,
+            Schedule::Interval(_) => panic!("{} parsed as an interval", expression),
+        }
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(allowed(&parse_field("5", 0, 59).unwrap()), [5]);
+        assert_eq!(allowed(&parse_field("1-5", 0, 7).unwrap()), [1, 2, 3, 4, 5]);
+        assert_eq!(allowed(&parse_field("*/15", 0, 59).unwrap()), [0, 15, 30, 45]);
+        assert_eq!(allowed(&parse_field("10/20", 0, 59).unwrap()), [10, 30, 50]);
+        assert_eq!(allowed(&parse_field("1-10/3", 1, 31).unwrap()), [1, 4, 7, 10]);
+        assert_eq!(allowed(&parse_field("1,3,20-22", 1, 31).unwrap()), [1, 3, 20, 21, 22]);
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        for field in ["*/0", "5-1", "60", "0-60", "a", "1-b", ""] {
+            assert!(parse_field(field, 0, 59).is_err(), "{}", field);
+        }
+        assert!(parse_field("0", 1, 31).is_err());
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        let expression = cron("0 0 * * 7");
+        assert_eq!(allowed(&expression.weekdays[..7]), [0]);
+        assert_eq!(expression.to_cron(), "0 0 * * 0");
+    }
+
+    #[test]
+    fn detects_unrestricted_days() {
+        let expression = cron("0 0 1 * *");
+        assert!(!expression.any_day && expression.any_weekday);
+        let expression = cron("0 0 * * 1-5");
+        assert!(expression.any_day && !expression.any_weekday);
+        // A field covering every value still counts as a restriction, as in cron
+        let expression = cron("0 0 1-31 * 0-7");
+        assert!(!expression.any_day && !expression.any_weekday);
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for schedule in ["", "* * * *", "* * * * * *", "0 24 * * *", "every 0h", "5x"] {
+            assert!(Schedule::parse(schedule).is_err(), "{}", schedule);
+        }
+    }
+
+    // 2024-01-15 is a Monday, outside of daylight saving time
+    #[test]
+    fn finds_next_cron_run() {
+        let now = local(2024, 1, 15, 10, 30);
+        let next = |schedule: &str| Schedule::parse(schedule).unwrap().next_after(now);
+        assert_eq!(next("*/15 * * * *"), Some(local(2024, 1, 15, 10, 45)));
+        assert_eq!(next("30 10 * * *"), Some(local(2024, 1, 16, 10, 30)));
+        assert_eq!(next("30 2 * * *"), Some(local(2024, 1, 16, 2, 30)));
+        assert_eq!(next("@weekly"), Some(local(2024, 1, 21, 0, 0)));
+        assert_eq!(next("@monthly"), Some(local(2024, 2, 1, 0, 0)));
+        assert_eq!(next("0 9 * * 1-5"), Some(local(2024, 1, 16, 9, 0)));
+        assert_eq!(next("0 12 29 2 *"), Some(local(2024, 2, 29, 12, 0)));
+    }
+
+    #[test]
+    fn matches_day_of_month_or_week() {
+        let now = local(2024, 1, 15, 10, 30);
+        let schedule = Schedule::parse("0 0 20 * 3").unwrap();
+        // Wednesday the 17th comes before the 20th
+        assert_eq!(schedule.next_after(now), Some(local(2024, 1, 17, 0, 0)));
+        let after = schedule.next_after(local(2024, 1, 17, 0, 0));
+        assert_eq!(after, Some(local(2024, 1, 20, 0, 0)));
+    }
+
+    #[test]
+    fn never_matching_schedule_has_no_next_run() {
+        let now = local(2024, 1, 15, 10, 30);
+        assert_eq!(Schedule::parse("0 0 31 2 *").unwrap().next_after(now), None);
+    }
+
+    #[test]
+    fn finds_next_interval_run() {
+        let now = local(2024, 1, 15, 10, 30);
+        let schedule = Schedule::parse("every 2h").unwrap();
+        assert_eq!(schedule.next_after(now), Some(local(2024, 1, 15, 12, 30)));
+        let schedule = Schedule::parse("1w").unwrap();
+        assert_eq!(schedule.next_after(now), Some(local(2024, 1, 22, 10, 30)));
+    }
+
+    #[test]
+    fn time_windows_accept_sunday_as_0_or_7() {
+        // 2024-01-14 is a Sunday
+        for days in ["0", "7", "6-7", "*"] {
+            let window = TimeWindow::parse(&format!("9-17 {}", days)).unwrap();
+            assert!(window.contains(local(2024, 1, 14, 9, 0)), "{}", days);
+            assert!(window.contains(local(2024, 1, 14, 17, 59)), "{}", days);
+            assert!(!window.contains(local(2024, 1, 14, 18, 0)), "{}", days);
+            assert!(!window.contains(local(2024, 1, 14, 8, 59)), "{}", days);
+        }
+        let weekdays = TimeWindow::parse("* 1-5").unwrap();
+        assert!(!weekdays.contains(local(2024, 1, 14, 12, 0)));
+        assert!(weekdays.contains(local(2024, 1, 15, 12, 0)));
+    }
+
+    #[test]
+    fn rejects_invalid_time_windows() {
+        for window in ["9-17", "9-17 1-5 *", "24 *", "* 8", ""] {
+            assert!(TimeWindow::parse(window).is_err(), "{}", window);
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        let cases = [("90s", 90), ("30m", 1800), (" 6h ", 21600), ("2d", 172800), ("1w", 604800)];
+        for (value, seconds) in cases {
+            let expected = Some(std::time::Duration::from_secs(seconds));
+            assert_eq!(parse_duration(value), expected, "{}", value);
+        }
+        for value in ["", "30", "0m", "h", "-5s", "1.5h", "5 m", "3y", "99999999999999999w"] {
+            assert_eq!(parse_duration(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn intervals_take_the_same_units_as_timeouts() {
+        for (schedule, seconds) in [("every 90s", 90), ("1w", 604800)] {
+            match Schedule::parse(schedule).unwrap() {
+                Schedule::Interval(interval) => assert_eq!(interval.num_seconds(), seconds),
+                Schedule::Cron(_) => panic!("{} parsed as a cron expression", schedule),
+            }
+        }
+        assert!(Schedule::parse("every 90s").unwrap().to_cron().is_err());
+        assert!(Schedule::parse("every 30s").unwrap().to_cron().is_err());
+        assert_eq!(Schedule::parse("every 120s").unwrap().to_cron().unwrap(), "*/2 * * * *");
+    }
+
+    #[test]
+    fn last_runs_are_kept_per_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("first.conf"), dir.path().join("second.conf"));
+        fs::write(&first, "").unwrap();
+        fs::write(&second, "").unwrap();
+        let state = dir.path().join("state");
+        assert_ne!(last_runs_path(&state, &first), last_runs_path(&state, &second));
+        // The same file reached through another path shares its state
+        let other = dir.path().join(".").join("first.conf");
+        assert_eq!(last_runs_path(&state, &other), last_runs_path(&state, &first));
+
+        let path = last_runs_path(&state, &first);
+        let time = local(2024, 1, 1, 12, 30);
+        let mut runs = LastRuns::open(Some(path.clone()));
+        runs.record("a", time).unwrap();
+        assert_eq!(LastRuns::open(Some(path)).get("a"), Some(time));
+        let path = last_runs_path(&state, &second);
+        assert_eq!(LastRuns::open(Some(path)).get("a"), None);
+    }
+}
@@ -11,18 +11,324 @@
 
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
+use std::collections::HashSet;
 
-use std::io::BufRead;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::ffi::CString;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::result::Result;
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
-use chrono::Utc;
+use chrono::{Local, NaiveDateTime, Utc};
 use std::fs;
 
+use crate::archive;
+use crate::crypto::{self, Key};
+use crate::format::Item;
+use crate::lock;
+use crate::manifest;
+use crate::mounts;
+use crate::priority::{self, IoPriority};
+use crate::rsync_args::check_rsync_arg;
+use crate::schedule::{self, parse_duration, Schedule, TimeWindow};
+
+enum Filter {
+    Include(String),
+    Exclude(String),
+}
+
+/// How much file metadata is preserved in the destination
+#[derive(Clone, Copy, PartialEq)]
+enum Preservation {
+    /// Modification times only
+    Basic,
+    /// Permissions, owners, groups, symlinks and devices (`-a`)
+    Archive,
+    /// Archive mode plus hard links, ACLs, extended attributes and numeric
+    /// user and group IDs
+    Full,
+}
+
+/// Where the key of an encrypted task comes from
+#[derive(Clone, Copy, PartialEq)]
+enum Encryption {
+    Passphrase,
+    KeyFile,
+}
+
+/// Filesystems that can't store Unix permissions, owners or special files
+const NON_UNIX_FILESYSTEMS: [&str; 6] = ["vfat", "msdos", "exfat", "ntfs", "ntfs3", "fuseblk"];
+
+/// Filesystems that may not support ACLs or extended attributes
+const LIMITED_XATTR_FILESYSTEMS: [&str; 4] = ["nfs", "nfs4", "cifs", "smb3"];
+
+/// Options given on the command line that apply to every task
+#[derive(Clone)]
+pub struct RunOptions {
+    pub quiet: bool,
+    pub debug: bool,
+    pub dry_run: bool,
+    pub include_hidden: bool,
+    pub rsync_args: Vec<String>,
+    /// Overrides the bandwidth limit of every task
+    pub bwlimit: Option<String>,
+    /// Overrides the niceness of every task
+    pub nice: Option<i32>,
+    /// Overrides the IO priority of every task
+    pub ionice: Option<IoPriority>,
+    /// rsync program to run, looked up on the PATH unless it is a path
+    pub rsync: PathBuf,
+}
+
+/// Parses the value of HIDDEN, which is `include` to back up hidden files
+/// and directories or `exclude` to skip them
+pub fn parse_hidden(value: &str) -> Result<bool, String> {
+    match value {
+        "include" => Ok(true),
+        "exclude" => Ok(false),
+        _ => Err(format!(
+            "Invalid HIDDEN value '{}'. Expected 'include' or 'exclude'.",
+            value
+        )),
+    }
+}
+
+/// Result of attempting to run a task
+pub enum TaskOutcome {
+    /// The task completed after the given number of retries
+    Completed(u32),
+    Skipped(String),
+    Failed(String),
+}
+
+/// Outcome of each task in a run, in the order in which they finished
+pub type Outcomes<'a> = Vec<(&'a Task, TaskOutcome)>;
+
+/// Finds a prerequisite of the task that didn't complete earlier in this run,
+/// in which case the task is skipped. Prerequisites that aren't part of the
+/// run are ignored.
+pub fn blocking_prerequisite(task: &Task, outcomes: &Outcomes) -> Option<String> {
+    outcomes.iter().find_map(|(other, outcome)| {
+        if !task.get_prerequisites().iter().any(|id| id == other.get_id()) {
+            return None;
+        }
+        match outcome {
+            TaskOutcome::Completed(_) => None,
+            TaskOutcome::Skipped(_) => Some(format!("Prerequisite {} was skipped", other.get_id())),
+            TaskOutcome::Failed(_) => Some(format!("Prerequisite {} failed", other.get_id())),
+        }
+    })
+}
+
+/// Default percentage of the files in a mirror's destination that may be
+/// deleted without asking for confirmation
+const DEFAULT_DELETE_THRESHOLD: f64 = 10.0;
+
+/// Delay before the first retry of a failed run, doubled for every further
+/// retry
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest delay between retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// rsync exit codes for failures that may not happen again: socket and
+/// protocol stream errors, timeouts and failures of the remote shell
+const TRANSIENT_EXIT_CODES: [i32; 5] = [10, 12, 30, 35, 255];
+
+/// How long rsync is given to exit after being asked to stop
+const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Name of the directory each backup task run writes to inside DST, in UTC
+pub const SNAPSHOT_FORMAT: &str = "%Y-%m-%d--%H_%M";
+
+/// Files that would be deleted from a mirror's destination
+pub struct DeletionPreview {
+    pub deletions: Vec<String>,
+    pub total: usize,
+}
+
+impl DeletionPreview {
+    /// Percentage of the files currently in the destination that would be
+    /// deleted
+    pub fn percentage(&self) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => self.deletions.len() as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+/// Differences between the source of a task and its destination or snapshot
+#[derive(Default)]
+pub struct Verification {
+    /// Files in the source that are missing from the backup
+    pub missing: Vec<String>,
+    /// Files in the backup that aren't in the source
+    pub extra: Vec<String>,
+    /// Files present in both that differ, with the attributes that differ
+    pub differing: Vec<(String, Vec<&'static str>)>,
+}
+
+impl Verification {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.differing.is_empty()
+    }
+
+    /// Sorts a line of rsync's --itemize-changes output into the list it
+    /// belongs to. Lines are formatted as `YXcstpoguax path`, where Y is the
+    /// update type, X the file type and the rest the changed attributes.
+    fn add_itemized(&mut self, line: &str) {
+        if let Some(path) = line.strip_prefix("*deleting") {
+            self.extra.push(path.trim_start().to_string());
+            return;
+        }
+        let (flags, path) = match line.split_once(' ') {
+            Some((flags, path)) if flags.len() == 11 => (flags.as_bytes(), path.to_string()),
+            _ => return,
+        };
+        if !b"<>ch.".contains(&flags[0]) {
+            return;
+        }
+        if flags[2..].iter().all(|&c| c == b'+') {
+            self.missing.push(path);
+            return;
+        }
+        let names = [
+            "checksum",
+            "size",
+            "modification time",
+            "permissions",
+            "owner",
+            "group",
+            "",
+            "ACL",
+            "extended attributes",
+        ];
+        let mut changes: Vec<&'static str> = flags[2..]
+            .iter()
+            .zip(names.iter())
+            .filter(|(&c, name)| c != b'.' && c != b' ' && !name.is_empty())
+            .map(|(_, &name)| name)
+            .collect();
+        // Directory times change whenever their contents do, and directories
+        // are never transferred themselves
+        let is_dir = flags[1] == b'd';
+        if is_dir {
+            changes.retain(|&change| change != "modification time");
+        }
+        if !changes.is_empty() || (flags[0] != b'.' && !is_dir) {
+            self.differing.push((path, changes));
+        }
+    }
+}
+
+/// Estimated size of a transfer compared with the space on the destination
+pub struct SpaceEstimate {
+    pub required: u64,
+    pub available: u64,
+    pub reserve: u64,
+}
+
+impl SpaceEstimate {
+    pub fn fits(&self) -> bool {
+        self.required.saturating_add(self.reserve) <= self.available
+    }
+}
+
+/// Parses a size in bytes with an optional K, M, G or T suffix (powers of
+/// 1024)
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&value[..i], 1 << 10),
+        (i, 'M') | (i, 'm') => (&value[..i], 1 << 20),
+        (i, 'G') | (i, 'g') => (&value[..i], 1 << 30),
+        (i, 'T') | (i, 't') => (&value[..i], 1 << 40),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Formats a duration in whole seconds, minutes or hours for display
+fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        secs if secs % 3600 == 0 => format!("{}h", secs / 3600),
+        secs if secs % 60 == 0 => format!("{}m", secs / 60),
+        secs => format!("{}s", secs),
+    }
+}
+
+/// Describes how many retries a run needed, for appending to its outcome
+pub fn describe_retries(retries: u32) -> String {
+    match retries {
+        0 => String::new(),
+        1 => String::from(" after 1 retry"),
+        _ => format!(" after {} retries", retries),
+    }
+}
+
+/// Formats a size in bytes for display
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
+
+/// Space available to unprivileged users on the filesystem containing the
+/// path, or its closest existing parent
+fn free_space(path: &Path) -> Result<u64, String> {
+    let existing = match path.ancestors().find(|path| path.exists()) {
+        Some(existing) => existing,
+        None => return Err(format!("{} doesn't exist", path.display())),
+    };
+    let c_path = match CString::new(existing.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return Err(format!("Invalid path {}", existing.display())),
+    };
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!(
+            "Failed to get free space for {}: {}",
+            existing.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Why a run of rsync failed and whether running it again might succeed
+struct RsyncFailure {
+    message: String,
+    transient: bool,
+}
+
+impl RsyncFailure {
+    fn permanent(message: String) -> Self {
+        RsyncFailure {
+            message,
+            transient: false,
+        }
+    }
+}
+
 pub struct Task {
-    id: String,
+    id: Option<String>,
     is_update: bool,
     always_confirm: bool,
     src: Option<PathBuf>,
@@ -32,15 +338,47 @@ pub struct Task {
     link_dest: Vec<PathBuf>,
     compare_dest: Vec<PathBuf>,
     exclude_others: bool,
+    preservation: Preservation,
+    mirror: bool,
+    max_delete: Option<u64>,
+    delete_threshold: f64,
+    include_hidden: Option<bool>,
+    filters: Vec<Filter>,
+    rsync_args: Vec<String>,
+    schedule: Option<Schedule>,
+    after: Vec<String>,
+    require_mount: Option<PathBuf>,
+    require_marker: Option<PathBuf>,
+    require_uuid: Option<String>,
+    min_free: u64,
     exclude_from: Option<PathBuf>,
     include_from: Option<PathBuf>,
     files_from: Option<PathBuf>,
+    encryption: Option<Encryption>,
+    key_file: Option<PathBuf>,
+    key: OnceLock<Key>,
+    bwlimit: Option<String>,
+    /// Lower bandwidth limit used instead of BWLIMIT when a run starts
+    /// during working hours
+    work_bwlimit: Option<String>,
+    work_hours: Option<TimeWindow>,
+    /// Priorities of the rsync processes. The archive of an encrypted task
+    /// is written at rsbackup's own priority.
+    nice: Option<i32>,
+    ionice: Option<IoPriority>,
+    /// Longest a run of rsync may take before it is stopped
+    timeout: Option<Duration>,
+    /// Passed to rsync as --timeout to stop it when no data is transferred
+    /// for that long
+    io_timeout: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
 }
 
 impl Task {
     fn new() -> Self {
         Task {
-            id: String::from("New Task"),
+            id: None,
             is_update: true,
             always_confirm: false,
             src: None,
@@ -50,9 +388,34 @@ impl Task {
             link_dest: Vec::new(),
             compare_dest: Vec::new(),
             exclude_others: false,
+            preservation: Preservation::Basic,
+            mirror: false,
+            max_delete: None,
+            delete_threshold: DEFAULT_DELETE_THRESHOLD,
+            include_hidden: None,
+            filters: Vec::new(),
+            rsync_args: Vec::new(),
+            schedule: None,
+            after: Vec::new(),
+            require_mount: None,
+            require_marker: None,
+            require_uuid: None,
+            min_free: 0,
             exclude_from: None,
             include_from: None,
             files_from: None,
+            encryption: None,
+            key_file: None,
+            key: OnceLock::new(),
+            bwlimit: None,
+            work_bwlimit: None,
+            work_hours: None,
+            nice: None,
+            ionice: None,
+            timeout: None,
+            io_timeout: None,
+            retries: 0,
+            retry_delay: DEFAULT_RETRY_DELAY,
         }
     }
 
@@ -63,21 +426,52 @@ impl Task {
         }
     }
 
-    pub fn run_task(&self, quiet: bool, debug: bool, dry_run: bool) -> Result<(), String> {
-        let mut args = vec![String::from("--exclude"), String::from(".*")];
-        args.push(String::from(match self.is_update {
-            true => "-ru",
-            false => "-rt",
-        }));
-        if !quiet {
-            args.push(String::from("-h"));
-            args.push(String::from("--progress"));
-            args.push(String::from("--verbose"));
+    /// Sets whether hidden files are backed up unless the task sets HIDDEN
+    pub fn set_default_hidden(&mut self, include: bool) {
+        self.include_hidden.get_or_insert(include);
+    }
+
+    fn includes_hidden(&self, options: &RunOptions) -> bool {
+        self.include_hidden.unwrap_or(options.include_hidden)
+    }
+
+    /// The rsync flag for the metadata the task preserves, which for update
+    /// tasks also skips files that are newer in the destination
+    fn mode_flag(&self) -> &'static str {
+        match (self.preservation, self.is_update) {
+            (Preservation::Basic, true) => "-ru",
+            (Preservation::Basic, false) => "-rt",
+            (Preservation::Archive, true) => "-au",
+            (Preservation::Archive, false) => "-a",
+            (Preservation::Full, true) => "-auHAX",
+            (Preservation::Full, false) => "-aHAX",
         }
+    }
 
+    /// Options selecting which files the task transfers
+    fn filter_args(&self, options: &RunOptions) -> Vec<String> {
+        let mut args = Vec::new();
+        if !self.includes_hidden(options) {
+            args.push(String::from("--exclude"));
+            args.push(String::from(".*"));
+        }
         if let Some(path) = &self.files_from {
             args.push(format!("--files-from={}", Task::path_to_string(path)));
         }
+        // rsync uses the first matching rule, so patterns given in the task
+        // itself take precedence over the ones in pattern files
+        for filter in &self.filters {
+            match filter {
+                Filter::Include(pattern) => {
+                    args.push(String::from("--include"));
+                    args.push(pattern.clone());
+                }
+                Filter::Exclude(pattern) => {
+                    args.push(String::from("--exclude"));
+                    args.push(pattern.clone());
+                }
+            }
+        }
         if let Some(path) = &self.exclude_from {
             args.push(format!("--exclude-from={}", Task::path_to_string(path)));
         }
@@ -88,7 +482,13 @@ impl Task {
             args.push(String::from("--exclude"));
             args.push(String::from("*"));
         }
+        args
+    }
 
+    /// Options naming the directories files are compared with or linked to
+    /// instead of being copied
+    fn compare_args(&self) -> Result<Vec<String>, String> {
+        let mut args = Vec::new();
         for path in &self.link_dest {
             args.push(format!("--link-dest={}", Task::path_to_string(path)));
         }
@@ -115,41 +515,704 @@ impl Task {
                 }
             };
         }
-        if dry_run {
+        Ok(args)
+    }
+
+    /// Directory a run of the task writes to: the destination of an update
+    /// task, a new snapshot of a backup task or the staging directory of an
+    /// encrypted task
+    fn run_target(&self) -> PathBuf {
+        let dst = self.dst.as_ref().unwrap();
+        if self.encryption.is_some() {
+            return self.staging_dir();
+        }
+        match self.is_update {
+            true => dst.clone(),
+            false => dst.join(Utc::now().format(SNAPSHOT_FORMAT).to_string()),
+        }
+    }
+
+    /// Local directory an encrypted task copies the source to before it is
+    /// archived and encrypted, so that no plaintext is written to the
+    /// destination. It is kept between runs so that rsync only has to
+    /// transfer the changes. Tasks sharing a destination have their own
+    /// staging directories, told apart by ID or, without one, by source.
+    fn staging_dir(&self) -> PathBuf {
+        let task = match &self.id {
+            Some(id) => lock::file_name(id),
+            None => lock::path_key(self.src.as_ref().unwrap()),
+        };
+        schedule::state_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("staging")
+            .join(format!("{}-{}", lock::path_key(self.dst.as_ref().unwrap()), task))
+    }
+
+    /// Bandwidth limit for a run starting now, if any
+    fn bandwidth_limit<'a>(&'a self, options: &'a RunOptions) -> Option<&'a str> {
+        if let Some(bwlimit) = &options.bwlimit {
+            return Some(bwlimit);
+        }
+        match (&self.work_hours, &self.work_bwlimit) {
+            (Some(hours), Some(bwlimit)) if hours.contains(Local::now()) => Some(bwlimit),
+            _ => self.bwlimit.as_deref(),
+        }
+    }
+
+    /// Creates an rsync command running with the task's CPU and IO
+    /// priorities
+    fn rsync_command(&self, options: &RunOptions) -> Command {
+        let mut cmd = Command::new(&options.rsync);
+        priority::apply(&mut cmd, options.nice.or(self.nice), options.ionice.or(self.ionice));
+        cmd
+    }
+
+    /// Builds the arguments for running rsync on the task. `extra` options
+    /// come after all other options, so they take precedence over the
+    /// task's own rsync options.
+    fn rsync_args(
+        &self,
+        options: &RunOptions,
+        target: &Path,
+        extra: &[&str],
+    ) -> Result<Vec<String>, String> {
+        let mut args = vec![String::from(self.mode_flag())];
+        if self.preservation == Preservation::Full {
+            args.push(String::from("--numeric-ids"));
+        }
+        if !options.quiet {
+            args.push(String::from("-h"));
+            args.push(String::from("--progress"));
+            args.push(String::from("--verbose"));
+        }
+        if self.mirror || self.encryption.is_some() {
+            // Deletions happen after the transfer so that an interrupted
+            // run doesn't leave the mirror with files missing. The staging
+            // directory of an encrypted task mirrors the source.
+            args.push(String::from("--delete"));
+            args.push(String::from("--delete-delay"));
+            if let Some(max) = self.max_delete {
+                args.push(format!("--max-delete={}", max));
+            }
+        }
+        if self.encryption.is_some() {
+            // Files excluded since the last run would otherwise stay in the
+            // staging directory and end up in the archive
+            args.push(String::from("--delete-excluded"));
+        }
+        if let Some(bwlimit) = self.bandwidth_limit(options) {
+            args.push(format!("--bwlimit={}", bwlimit));
+        }
+        if let Some(timeout) = self.io_timeout {
+            args.push(format!("--timeout={}", timeout.as_secs()));
+        }
+        if self.retries > 0 || self.timeout.is_some() {
+            // Keep partially transferred files so that a retry or the next
+            // run resumes them, also when rsync is stopped after TIMEOUT
+            args.push(String::from("--partial"));
+        }
+        args.extend(self.filter_args(options));
+        args.extend(self.compare_args()?);
+        args.extend(options.rsync_args.iter().cloned());
+        args.extend(self.rsync_args.iter().cloned());
+        if options.dry_run {
             args.push(String::from("--dry-run"));
         }
-        let src = self.src.as_ref().unwrap();
+        args.extend(extra.iter().map(|arg| arg.to_string()));
+        args.push(Task::path_to_string(self.src.as_ref().unwrap()));
+        args.push(Task::path_to_string(&target.to_path_buf()));
+        Ok(args)
+    }
+
+    /// Determines which files a mirror task would delete from its destination
+    /// by running rsync in dry-run mode
+    pub fn preview_deletions(&self, options: &RunOptions) -> Result<DeletionPreview, String> {
+        let mut preview_options = options.clone();
+        preview_options.quiet = true;
+        preview_options.dry_run = true;
+        let args = self.rsync_args(&preview_options, &self.run_target(), &["--itemize-changes"])?;
+        let output = match self.rsync_command(options).args(args).output() {
+            Ok(output) => output,
+            Err(why) => return Err(format!("Failed to run rsync: {}", why)),
+        };
+        if !output.status.success() {
+            return Err(format!(
+                "rsync preview failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let deletions = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.strip_prefix("*deleting"))
+            .map(|path| path.trim_start().to_string())
+            .collect();
+        Ok(DeletionPreview {
+            deletions,
+            total: Task::count_entries(self.dst.as_ref().unwrap()),
+        })
+    }
+
+    /// Estimates how much data the task will write by running rsync in
+    /// dry-run mode and compares it with the free space on the destination
+    pub fn estimate_space(&self, options: &RunOptions) -> Result<SpaceEstimate, String> {
+        let mut preview_options = options.clone();
+        preview_options.quiet = true;
+        preview_options.dry_run = true;
+        // Sizes are parsed as plain numbers, even if the task's own rsync
+        // options include -h
+        let extra = ["--stats", "--no-human-readable"];
+        let args = self.rsync_args(&preview_options, &self.run_target(), &extra)?;
+        let output = match self.rsync_command(options).args(args).output() {
+            Ok(output) => output,
+            Err(why) => return Err(format!("Failed to run rsync: {}", why)),
+        };
+        if !output.status.success() {
+            return Err(format!(
+                "rsync size estimate failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        // Encrypted tasks write an archive of the whole source to the
+        // destination, not just the files that changed
+        let field = match self.encryption {
+            Some(_) => "Total file size:",
+            None => "Total transferred file size:",
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let required = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(field))
+            .and_then(|size| {
+                // Sizes contain thousands separators, e.g. "1,234,567 bytes"
+                let digits: String = size
+                    .chars()
+                    .take_while(|c| !c.is_alphabetic())
+                    .filter(|c| c.is_ascii_digit())
+                    .collect();
+                digits.parse().ok()
+            });
+        match required {
+            Some(required) => Ok(SpaceEstimate {
+                required,
+                available: free_space(self.dst.as_ref().unwrap())?,
+                reserve: self.min_free,
+            }),
+            None => Err(String::from("Transfer size not found in rsync output")),
+        }
+    }
+
+    /// Lists the snapshots of a backup task from oldest to newest
+    pub fn snapshots(&self) -> Vec<String> {
+        let encrypted = self.encryption.is_some();
+        let mut snapshots: Vec<String> = match fs::read_dir(self.dst.as_ref().unwrap()) {
+            Ok(entries) => entries
+                .flatten()
+                .filter_map(|entry| {
+                    let name = entry.file_name().into_string().ok()?;
+                    match encrypted {
+                        true => name.strip_suffix(crypto::EXTENSION).map(String::from),
+                        false if entry.path().is_dir() => Some(name),
+                        false => None,
+                    }
+                })
+                .filter(|name| NaiveDateTime::parse_from_str(name, SNAPSHOT_FORMAT).is_ok())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        snapshots.sort();
+        snapshots
+    }
+
+    /// Lists the directories that may hold earlier versions of the backed up
+    /// files by name: the snapshots of the task and, with [COMPARE BPATH],
+    /// every directory in the backup path
+    pub fn snapshot_dirs(&self) -> Vec<(String, PathBuf)> {
         let dst = self.dst.as_ref().unwrap();
-        args.push(Task::path_to_string(src));
+        let mut dirs: Vec<(String, PathBuf)> = self
+            .snapshots()
+            .into_iter()
+            .map(|name| {
+                let path = dst.join(&name);
+                (name, path)
+            })
+            .collect();
+        if self.compare_paths {
+            let entries = fs::read_dir(self.backup_path.as_ref().unwrap());
+            for entry in entries.into_iter().flatten().flatten() {
+                let path = entry.path();
+                let known = dirs
+                    .iter()
+                    .any(|(_, dir)| fs::canonicalize(dir).ok() == fs::canonicalize(&path).ok());
+                if path.is_dir() && !known {
+                    dirs.push((entry.file_name().to_string_lossy().to_string(), path));
+                }
+            }
+            dirs.sort();
+        }
+        dirs
+    }
+
+    /// Lists the directories that together hold the files of a snapshot,
+    /// newest first. With [COMPARE BPATH], a snapshot only contains the files
+    /// that changed since the earlier ones, so all earlier directories are
+    /// included; otherwise the snapshot is complete on its own.
+    pub fn snapshot_layers(&self, snapshot: &str) -> Result<Vec<PathBuf>, String> {
+        if self.encryption.is_some() {
+            return Err(format!(
+                "Snapshots of task {} are encrypted. Use materialize to decrypt one.",
+                self.get_id()
+            ));
+        }
+        let path = self.snapshot_path(snapshot)?;
+        if !self.compare_paths {
+            return Ok(vec![path]);
+        }
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let mut layers: Vec<PathBuf> = self
+            .snapshot_dirs()
+            .into_iter()
+            .filter(|(dir, _)| *dir < name)
+            .map(|(_, dir)| dir)
+            .collect();
+        layers.push(path);
+        layers.reverse();
+        Ok(layers)
+    }
+
+    /// Finds the directory holding a snapshot of a backup task, or the
+    /// archive for encrypted tasks. The snapshot is either given by name or
+    /// is the latest one.
+    pub fn snapshot_path(&self, snapshot: &str) -> Result<PathBuf, String> {
         if self.is_update {
-            args.push(Task::path_to_string(dst));
-        } else {
-            args.push(format!(
-                "{}/{}",
-                Task::path_to_string(&dst),
-                Utc::now().format("%Y-%m-%d--%H_%M")
+            return Err(format!("Task {} is an update task and has no snapshots.", self.get_id()));
+        }
+        let snapshots = self.snapshots();
+        let name = match snapshot {
+            "latest" => snapshots.last(),
+            name => snapshots.iter().find(|snapshot| *snapshot == name),
+        };
+        match name {
+            Some(name) if self.encryption.is_some() => Ok(self
+                .dst
+                .as_ref()
+                .unwrap()
+                .join(format!("{}{}", name, crypto::EXTENSION))),
+            Some(name) => Ok(self.dst.as_ref().unwrap().join(name)),
+            None if snapshots.is_empty() => Err(format!("Task {} has no snapshots.", self.get_id())),
+            None => Err(format!(
+                "Snapshot {} not found. Available snapshots: {}",
+                snapshot,
+                snapshots.join(", ")
+            )),
+        }
+    }
+
+    /// Finds the directory in the source and the directories in the layers of
+    /// a snapshot that hold the same files. Like rsync, a source without a
+    /// trailing slash is copied into a directory of the same name.
+    pub fn source_tree(&self, layers: &[PathBuf]) -> Result<(PathBuf, Vec<PathBuf>), String> {
+        let src = self.src.as_ref().unwrap();
+        if !src.is_dir() {
+            return Err(format!(
+                "Source {} is not a local directory.",
+                Task::path_to_string(src)
             ));
         }
-        if debug {
-            println!("DEBUG: rsync {}", args.join(" "));
+        match src.file_name() {
+            Some(name) if !src.as_os_str().as_bytes().ends_with(b"/") => Ok((
+                src.clone(),
+                layers.iter().map(|layer| layer.join(name)).collect(),
+            )),
+            _ => Ok((src.clone(), layers.to_vec())),
+        }
+    }
+
+    /// Extracts the path of a regular file from a line of `rsync --list-only`
+    /// output, which starts with the permissions, size, date and time
+    fn listed_file(line: &str) -> Option<&str> {
+        if !line.starts_with('-') {
+            return None;
+        }
+        let mut rest = line;
+        for _ in 0..4 {
+            rest = rest.trim_start();
+            rest = &rest[rest.find(' ')?..];
+        }
+        Some(&rest[1..])
+    }
+
+    /// Lists the regular files in the source that the task backs up, applying
+    /// its filters, by path relative to the source directory
+    pub fn source_files(&self, options: &RunOptions) -> Result<HashSet<String>, String> {
+        let src = self.src.as_ref().unwrap();
+        let mut args = vec![
+            String::from(self.mode_flag()),
+            String::from("--list-only"),
+            String::from("--no-human-readable"),
+        ];
+        args.extend(self.filter_args(options));
+        args.push(Task::path_to_string(src));
+        let output = match self.rsync_command(options).args(args).output() {
+            Ok(output) => output,
+            Err(why) => return Err(format!("Failed to run rsync: {}", why)),
+        };
+        if !output.status.success() {
+            return Err(format!(
+                "Listing the source failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let prefix = match src.file_name() {
+            Some(name) if !src.as_os_str().as_bytes().ends_with(b"/") => {
+                format!("{}/", name.to_string_lossy())
+            }
+            _ => String::new(),
+        };
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(Task::listed_file)
+            .filter_map(|path| path.strip_prefix(prefix.as_str()))
+            .map(String::from)
+            .collect())
+    }
+
+    /// Compares the source with the destination of an update task or a
+    /// snapshot of a backup task using rsync in dry-run mode, applying the
+    /// task's filters. Sizes and modification times are compared unless the
+    /// task doesn't preserve times, in which case only sizes are. With
+    /// `checksum`, the contents of all files are compared instead.
+    pub fn verify(
+        &self,
+        options: &RunOptions,
+        target: &Path,
+        checksum: bool,
+    ) -> Result<Verification, String> {
+        let mut args = vec![
+            self.mode_flag().replace('u', ""),
+            String::from("--dry-run"),
+            String::from("--itemize-changes"),
+            String::from("--delete"),
+        ];
+        if checksum {
+            args.push(String::from("--checksum"));
+        } else if self.is_update && self.preservation == Preservation::Basic {
+            args.push(String::from("--size-only"));
+        }
+        if self.preservation == Preservation::Full {
+            args.push(String::from("--numeric-ids"));
+        }
+        args.extend(self.filter_args(options));
+        args.extend(self.compare_args()?);
+        args.push(Task::path_to_string(self.src.as_ref().unwrap()));
+        args.push(Task::path_to_string(&target.to_path_buf()));
+        let output = match self.rsync_command(options).args(args).output() {
+            Ok(output) => output,
+            Err(why) => return Err(format!("Failed to run rsync: {}", why)),
+        };
+        if !output.status.success() {
+            return Err(format!(
+                "rsync comparison failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let mut verification = Verification::default();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            verification.add_itemized(line);
+        }
+        Ok(verification)
+    }
+
+    fn count_entries(dir: &Path) -> usize {
+        match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .flatten()
+                .map(|entry| match entry.file_type() {
+                    Ok(t) if t.is_dir() => 1 + Task::count_entries(&entry.path()),
+                    _ => 1,
+                })
+                .sum(),
+            Err(_) => 0,
+        }
+    }
+
+    fn check_status(status: ExitStatus) -> Result<(), RsyncFailure> {
+        match status.success() {
+            true => Ok(()),
+            false => Err(RsyncFailure {
+                message: format!(
+                    "rsync failed with exit code {}",
+                    match status.code() {
+                        Some(code) => format!("{}", code),
+                        None => String::from("(?)"),
+                    }
+                ),
+                transient: status.code().is_some_and(|code| TRANSIENT_EXIT_CODES.contains(&code)),
+            }),
+        }
+    }
+
+    /// Waits for rsync to exit, stopping it if it runs for longer than the
+    /// task's timeout. rsync is asked to stop before being killed so that it
+    /// keeps partially transferred files.
+    fn wait_for(&self, child: &mut Child) -> Result<(), RsyncFailure> {
+        let failed = |why| RsyncFailure::permanent(format!("Failed to run rsync: {}", why));
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Task::check_status(child.wait().map_err(failed)?),
+        };
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = child.try_wait().map_err(failed)? {
+                return Task::check_status(status);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        unsafe {
+            libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        let deadline = Instant::now() + TERMINATION_GRACE_PERIOD;
+        while child.try_wait().map_err(failed)?.is_none() {
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                child.wait().map_err(failed)?;
+                break;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Err(RsyncFailure {
+            message: format!("rsync stopped after running for {}", format_duration(timeout)),
+            transient: true,
+        })
+    }
+
+    /// Reads a pipe in the background, passing on what is read as it
+    /// arrives, tagged with the given index, until the pipe is closed
+    fn read_pipe(
+        mut pipe: impl Read + Send + 'static,
+        index: usize,
+        sender: mpsc::Sender<(usize, Vec<u8>)>,
+    ) {
+        thread::spawn(move || {
+            let mut buffer = [0; 8192];
+            while let Ok(read @ 1..) = pipe.read(&mut buffer) {
+                if sender.send((index, buffer[..read].to_vec())).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Runs rsync once, collecting its output if requested instead of
+    /// letting it print to the terminal
+    fn run_rsync(
+        &self,
+        options: &RunOptions,
+        args: &[String],
+        output: Option<&mut String>,
+    ) -> Result<(), RsyncFailure> {
+        let mut cmd = self.rsync_command(options);
+        cmd.args(args);
+        if output.is_some() {
+            cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let mut child = cmd
+            .spawn()
+            .map_err(|why| RsyncFailure::permanent(format!("Failed to run rsync: {}", why)))?;
+        // The pipes are read while waiting so that rsync doesn't block on a
+        // full pipe
+        let (sender, receiver) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            Task::read_pipe(stdout, 0, sender.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            Task::read_pipe(stderr, 1, sender.clone());
+        }
+        drop(sender);
+        let result = self.wait_for(&mut child);
+        if let Some(output) = output {
+            // Processes started by rsync, such as ssh, may keep the pipes
+            // open after rsync was stopped. Both pipes are read until the
+            // same deadline so that neither is cut short by the other.
+            let deadline = Instant::now() + TERMINATION_GRACE_PERIOD;
+            let remaining = || deadline.saturating_duration_since(Instant::now());
+            let mut buffers = [Vec::new(), Vec::new()];
+            while let Ok((index, chunk)) = receiver.recv_timeout(remaining()) {
+                buffers[index].extend(chunk);
+            }
+            for buffer in buffers {
+                output.push_str(&String::from_utf8_lossy(&buffer));
+            }
+        }
+        result
+    }
+
+    /// How long to wait before the retry following the given number of
+    /// earlier retries. The delay doubles with each retry up to an hour.
+    fn delay_before_retry(&self, retries: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(1 << retries.min(16))
+            .min(MAX_RETRY_DELAY)
+    }
+
+    /// Runs rsync, running it again after transient failures as many times as
+    /// the task allows with exponentially increasing delays. Returns the
+    /// number of retries needed.
+    fn run_with_retries(
+        &self,
+        options: &RunOptions,
+        args: &[String],
+        mut output: Option<&mut String>,
+    ) -> Result<u32, String> {
+        let mut retries = 0;
+        loop {
+            let failure = match self.run_rsync(options, args, output.as_deref_mut()) {
+                Ok(_) => return Ok(retries),
+                Err(failure) => failure,
+            };
+            if !failure.transient || retries >= self.retries {
+                return Err(format!("{}{}", failure.message, describe_retries(retries)));
+            }
+            let delay = self.delay_before_retry(retries);
+            let note = format!(
+                "{}. Retrying in {} ({} of {}).",
+                failure.message,
+                format_duration(delay),
+                retries + 1,
+                self.retries
+            );
+            match output.as_deref_mut() {
+                Some(output) => {
+                    output.push_str(&note);
+                    output.push('\n');
+                }
+                None => println!("{}", note),
+            }
+            thread::sleep(delay);
+            retries += 1;
+        }
+    }
+
+    /// Writes the checksum manifest of a completed snapshot, or the
+    /// encrypted archive of the staging directory for encrypted tasks
+    fn finish_snapshot(&self, options: &RunOptions, target: &Path) -> Result<(), String> {
+        if self.is_update || options.dry_run {
             return Ok(());
         }
-        let mut cmd = Command::new("rsync");
-        match cmd.args(args).spawn() {
-            Ok(mut child) => match child.wait() {
-                Ok(status) => match status.success() {
-                    true => Ok(()),
-                    false => Err(format!(
-                        "rsync failed with exit code {}",
-                        match status.code() {
-                            Some(code) => format!("{}", code),
-                            None => String::from("(?)"),
-                        }
-                    )),
-                },
-                Err(why) => Err(format!("Failed to run rsync: {}", why)),
-            },
-            Err(why) => Err(format!("Failed to run rsync: {}", why)),
+        if self.encryption.is_some() {
+            let name = Utc::now().format(SNAPSHOT_FORMAT).to_string();
+            let output = self
+                .dst
+                .as_ref()
+                .unwrap()
+                .join(format!("{}{}", name, crypto::EXTENSION));
+            return archive::export_encrypted(&name, &[target.to_path_buf()], &output, self.key()?)
+                .map(|_| ())
+                .map_err(|why| format!("Failed to write encrypted snapshot: {}", why));
+        }
+        let mut cache = manifest::InodeCache::from_manifests(self.dst.as_ref().unwrap());
+        manifest::write(target, &mut cache)
+            .map(|_| ())
+            .map_err(|why| format!("Snapshot written, but failed to write its manifest: {}", why))
+    }
+
+    /// Runs the task, returning the number of retries it needed
+    pub fn run_task(&self, options: &RunOptions) -> Result<u32, String> {
+        let target = self.run_target();
+        let args = self.rsync_args(options, &target, &[])?;
+        if options.debug {
+            println!("DEBUG: rsync {}", args.join(" "));
+            return Ok(0);
+        }
+        let retries = self.run_with_retries(options, &args, None)?;
+        self.finish_snapshot(options, &target).map(|_| retries)
+    }
+
+    /// Runs the task with its output collected instead of printed, so that
+    /// tasks running in parallel don't interleave their output. Standard error
+    /// follows standard output.
+    pub fn run_task_buffered(&self, options: &RunOptions) -> (Result<u32, String>, String) {
+        let target = self.run_target();
+        let args = match self.rsync_args(options, &target, &[]) {
+            Ok(args) => args,
+            Err(why) => return (Err(why), String::new()),
+        };
+        if options.debug {
+            return (Ok(0), format!("DEBUG: rsync {}\n", args.join(" ")));
+        }
+        let mut text = String::new();
+        let result = self
+            .run_with_retries(options, &args, Some(&mut text))
+            .and_then(|retries| self.finish_snapshot(options, &target).map(|_| retries));
+        (result, text)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    fn key(&self) -> Result<&Key, String> {
+        self.key
+            .get()
+            .ok_or_else(|| format!("The key of task {} hasn't been loaded.", self.get_id()))
+    }
+
+    /// Loads the key of an encrypted task, asking for its passphrase if
+    /// needed and allowed, and checks it against the latest snapshot. Also
+    /// creates the staging directory. Does nothing for other tasks.
+    pub fn unlock(&self, interactive: bool) -> Result<(), String> {
+        let encryption = match self.encryption {
+            Some(encryption) if self.key.get().is_none() => encryption,
+            _ => return Ok(()),
+        };
+        let latest = self.snapshot_path("latest").ok();
+        let key = match encryption {
+            Encryption::KeyFile => Key::from_file(self.key_file.as_ref().unwrap())?,
+            Encryption::Passphrase => Key::passphrase(self.get_id(), interactive, latest.is_none())?,
+        };
+        if let Some(latest) = latest {
+            fs::File::open(&latest)
+                .and_then(|file| key.decrypt(file))
+                .map_err(|why| {
+                    format!(
+                        "Key doesn't match the latest snapshot {}: {}",
+                        Task::path_to_string(&latest),
+                        why
+                    )
+                })?;
+        }
+        let staging = self.staging_dir();
+        fs::create_dir_all(&staging).map_err(|why| {
+            format!("Failed to create {}: {}", Task::path_to_string(&staging), why)
+        })?;
+        let _ = self.key.set(key);
+        Ok(())
+    }
+
+    /// Decrypts a snapshot of an encrypted task into a directory
+    pub fn extract_snapshot(&self, snapshot: &str, target: &Path) -> Result<String, String> {
+        archive::extract_encrypted(&self.snapshot_path(snapshot)?, self.key()?, target)
+    }
+
+    /// Decrypts a snapshot of an encrypted task without keeping the
+    /// plaintext, which checks that it is intact
+    pub fn check_snapshot(&self, snapshot: &str) -> Result<(), String> {
+        let path = self.snapshot_path(snapshot)?;
+        let key = self.key()?;
+        fs::File::open(&path)
+            .and_then(|file| key.decrypt(file))
+            .and_then(|mut decrypted| std::io::copy(&mut decrypted, &mut std::io::sink()))
+            .map(|_| ())
+            .map_err(|why| format!("Failed to decrypt {}: {}", Task::path_to_string(&path), why))
+    }
+
+    /// Identifies the device holding the destination, so that tasks writing
+    /// to the same drive can be kept from running at the same time. Falls back
+    /// to the destination path if it can't be determined.
+    pub fn destination_group(&self) -> String {
+        let dst = self.dst.as_ref().unwrap();
+        match dst.ancestors().find_map(|path| fs::metadata(path).ok()) {
+            Some(metadata) => format!("device {}", metadata.dev()),
+            None => Task::path_to_string(dst),
         }
     }
 
@@ -157,12 +1220,40 @@ impl Task {
         self.always_confirm
     }
 
+    pub fn get_schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref()
+    }
+
+    /// IDs of the tasks that must run before this one
+    pub fn get_prerequisites(&self) -> &[String] {
+        &self.after
+    }
+
+    pub fn is_mirror(&self) -> bool {
+        self.mirror
+    }
+
+    /// Percentage of the destination's files that may be deleted by a
+    /// mirror task without asking for confirmation
+    pub fn get_delete_threshold(&self) -> f64 {
+        self.delete_threshold
+    }
+
     pub fn is_update_task(&self) -> bool {
         self.is_update
     }
 
+    /// ID of the task, or a placeholder shared by all tasks without one
     pub fn get_id(&self) -> &str {
-        self.id.as_str()
+        self.id.as_deref().unwrap_or("New Task")
+    }
+
+    pub fn has_id(&self) -> bool {
+        self.id.is_some()
+    }
+
+    pub fn get_destination(&self) -> &Path {
+        self.dst.as_ref().unwrap()
     }
 
     pub fn get_description(&self) -> String {
@@ -173,94 +1264,45 @@ impl Task {
         )
     }
 
-    pub fn from_reader(reader: &mut impl BufRead, debug: bool) -> Result<Self, String> {
+    /// Constructs a task from the parameters and flags given in its
+    /// configuration entry
+    pub fn from_items(items: &[Item], is_update: bool, debug: bool) -> Result<Self, String> {
         let mut task = Task::new();
-        let mut type_determined = false;
-        loop {
-            let mut line1 = String::new();
-            match reader.read_line(&mut line1) {
-                Ok(len) => {
-                    if len == 0 {
-                        return Err(String::from("EOF"));
-                    }
-                }
-                Err(err) => {
-                    return Err(err.to_string());
-                }
+        task.is_update = is_update;
+        for item in items {
+            match item {
+                Item::Parameter(key, value) => task.set_parameter(key, value)?,
+                Item::Flag(flag) => task.set_flag(flag)?,
             }
-            if line1.starts_with("#") || line1.len() == 1 {
-                continue;
+        }
+        if task.compare_paths && task.backup_path.is_none() {
+            return Err(String::from(
+                "[COMPARE BPATH] specified but no backup path given.",
+            ));
+        }
+        match (task.encryption, task.key_file.is_some()) {
+            (Some(Encryption::KeyFile), false) => {
+                return Err(String::from("ENCRYPT=keyfile specified but no KEYFILE given."));
             }
-            let line = line1.trim();
-            if !type_determined {
-                match line {
-                    "[BACKUP]" => task.is_update = false,
-                    "[UPDATE]" => {}
-                    _ => {
-                        return Err(String::from(
-                            "Failed to parse configuration file. Could not find task.",
-                        ));
-                    }
-                };
-                type_determined = true;
-                continue;
+            (Some(Encryption::Passphrase), true) | (None, true) => {
+                return Err(String::from("KEYFILE given but ENCRYPT isn't set to keyfile."));
             }
-            if line == "[END]" {
-                break;
+            _ => {}
+        }
+        match (&task.work_bwlimit, &task.work_hours) {
+            (Some(_), None) => {
+                return Err(String::from("WORK_BWLIMIT given but no WORK_HOURS."));
             }
-            if let Some(path) = line.strip_prefix("SRC=") {
-                task.src = Some(PathBuf::from(path));
-            } else if let Some(path) = line.strip_prefix("DST=") {
-                task.dst = Some(PathBuf::from(path));
-            } else if let Some(path) = line.strip_prefix("EXFR=") {
-                task.exclude_from = Some(PathBuf::from(path));
-            } else if let Some(path) = line.strip_prefix("INFR=") {
-                task.include_from = Some(PathBuf::from(path));
-            } else if let Some(path) = line.strip_prefix("FIFR=") {
-                task.files_from = Some(PathBuf::from(path));
-            } else if let Some(path) = line.strip_prefix("BPATH=") {
-                if task.is_update {
-                    return Err(String::from(
-                        "Unexpected BPATH parameter in update task configuration.",
-                    ));
-                } else {
-                    task.backup_path = Some(PathBuf::from(path));
-                }
-            } else if let Some(path) = line.strip_prefix("CDST=") {
-                task.compare_dest.push(PathBuf::from(path));
-            } else if let Some(path) = line.strip_prefix("LDST=") {
-                task.link_dest.push(PathBuf::from(path));
-            } else if let Some(name) = line.strip_prefix("ID=") {
-                task.id = name.to_string();
-            } else {
-                match line {
-                    "[EXCLUDE OTHERS]" => {
-                        task.exclude_others = true;
-                    }
-                    "[CONFIRM]" => {
-                        task.always_confirm = true;
-                    }
-                    "[COMPARE BPATH]" => {
-                        if task.is_update {
-                            return Err(String::from(
-                                "Unexpected [COMPARE BPATH] tag in update task configuration.",
-                            ));
-                        } else {
-                            task.compare_paths = true;
-                        }
-                    }
-                    _ => {
-                        return Err(format!("Unexpected line '{}' in configuration.", line));
-                    }
-                };
+            (None, Some(_)) => {
+                return Err(String::from("WORK_HOURS given but no WORK_BWLIMIT."));
             }
+            _ => {}
         }
-        if !type_determined {
-            return Err(String::from("EOF"));
-        }
-        if task.compare_paths && task.backup_path.is_none() {
+        if task.encryption.is_some()
+            && (task.compare_paths || !task.link_dest.is_empty() || !task.compare_dest.is_empty())
+        {
             return Err(String::from(
-                "[COMPARE BPATH] specified but no backup path given.",
+                "Encrypted tasks can't use LDST, CDST or [COMPARE BPATH].",
             ));
         }
         match task.src {
@@ -278,7 +1320,9 @@ impl Task {
         };
         match task.dst {
             Some(ref path) => {
-                if !debug && !path.exists() {
+                // Destinations on removable drives only exist while the drive
+                // is connected, which is checked before the task runs
+                if !debug && !path.exists() && !task.has_requirements() {
                     return Err(format!(
                         "Destination path {} nonexistent or inaccessible.",
                         Task::path_to_string(path)
@@ -291,4 +1335,579 @@ impl Task {
         };
         Ok(task)
     }
+
+    fn has_requirements(&self) -> bool {
+        self.require_mount.is_some() || self.require_marker.is_some() || self.require_uuid.is_some()
+    }
+
+    /// Checks that the destination is available, e.g. that the drive it is
+    /// on is mounted, before the task runs. Without this, rsync would fill the
+    /// empty mount point on the root filesystem when the drive is missing.
+    pub fn check_destination(&self) -> Result<(), String> {
+        let dst = self.dst.as_ref().unwrap();
+        if let Some(mount_point) = &self.require_mount {
+            let mounted = match fs::canonicalize(mount_point) {
+                Ok(path) => mounts::mounts().iter().any(|mount| mount.mount_point == path),
+                Err(_) => false,
+            };
+            if !mounted {
+                return Err(format!(
+                    "{} is not mounted",
+                    Task::path_to_string(mount_point)
+                ));
+            }
+        }
+        if let Some(marker) = &self.require_marker {
+            let marker = dst.join(marker);
+            if !marker.exists() {
+                return Err(format!(
+                    "Marker file {} not found",
+                    Task::path_to_string(&marker)
+                ));
+            }
+        }
+        if let Some(uuid) = &self.require_uuid {
+            // Files on a filesystem report the device number of the block
+            // device it was mounted from
+            let device = match fs::metadata(Path::new("/dev/disk/by-uuid").join(uuid)) {
+                Ok(metadata) => metadata.rdev(),
+                Err(_) => return Err(format!("No filesystem with UUID {} is connected", uuid)),
+            };
+            match dst.ancestors().find_map(|path| fs::metadata(path).ok()) {
+                Some(metadata) if metadata.dev() == device => {}
+                _ => {
+                    return Err(format!(
+                        "{} is not on the filesystem with UUID {}",
+                        Task::path_to_string(dst),
+                        uuid
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks for potential problems with the task that don't prevent it from
+    /// running and returns a warning for each one
+    pub fn check(&self, options: &RunOptions) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Some(src) = &self.src {
+            if !self.includes_hidden(options) && src.is_dir() {
+                // Stop early since sources can be large
+                const MAX_EXAMPLES: usize = 3;
+                let mut hidden = Vec::new();
+                Task::find_hidden(src, MAX_EXAMPLES, &mut hidden);
+                if !hidden.is_empty() {
+                    warnings.push(format!(
+                        "Hidden files or directories in {} will be skipped, e.g. {}. Set HIDDEN=include to back them up.",
+                        Task::path_to_string(src),
+                        hidden.join(", ")
+                    ));
+                }
+            }
+        }
+        if self.preservation != Preservation::Basic {
+            warnings.extend(self.check_preservation());
+        }
+        if let Some(schedule) = &self.schedule {
+            if schedule.next_after(Local::now()).is_none() {
+                warnings.push(String::from("Schedule never matches, so the task will never run."));
+            }
+        }
+        if self.min_free > 0 {
+            if let Ok(available) = free_space(self.dst.as_ref().unwrap()) {
+                if available < self.min_free {
+                    warnings.push(format!(
+                        "Only {} free on the destination, less than MIN_FREE ({}).",
+                        format_size(available),
+                        format_size(self.min_free)
+                    ));
+                }
+            }
+        }
+        if let Some(mount_point) = &self.require_mount {
+            if !self.dst.as_ref().unwrap().starts_with(mount_point) {
+                warnings.push(format!(
+                    "Destination is not inside the required mount point {}.",
+                    Task::path_to_string(mount_point)
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Checks whether the destination filesystem can store the metadata the
+    /// task is configured to preserve
+    fn check_preservation(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let dst = self.dst.as_ref().unwrap();
+        if let Some(mount) = mounts::mount_for(dst) {
+            let fs_type = mount.fs_type.as_str();
+            if NON_UNIX_FILESYSTEMS.contains(&fs_type) {
+                warnings.push(format!(
+                    "Destination {} is on a {} filesystem, which can't store permissions, owners, symlinks or device files.",
+                    Task::path_to_string(dst),
+                    fs_type
+                ));
+            } else if self.preservation == Preservation::Full
+                && LIMITED_XATTR_FILESYSTEMS.contains(&fs_type)
+            {
+                warnings.push(format!(
+                    "Destination {} is on a {} filesystem, which may not store ACLs or extended attributes.",
+                    Task::path_to_string(dst),
+                    fs_type
+                ));
+            }
+        }
+        // Only root can set the owner of the copied files
+        if unsafe { libc::geteuid() } != 0 {
+            warnings.push(String::from(
+                "Not running as root, so file owners won't be preserved.",
+            ));
+        }
+        warnings
+    }
+
+    /// Collects up to `limit` hidden entries under the given directory that
+    /// rsync would exclude. Hidden directories are excluded as a whole, so
+    /// their contents are not searched.
+    fn find_hidden(dir: &Path, limit: usize, found: &mut Vec<String>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            if found.len() >= limit {
+                return;
+            }
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                found.push(Task::path_to_string(&path));
+            } else if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                Task::find_hidden(&path, limit, found);
+            }
+        }
+    }
+
+    fn set_parameter(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "SRC" => self.src = Some(PathBuf::from(value)),
+            "DST" => self.dst = Some(PathBuf::from(value)),
+            "EXFR" => self.exclude_from = Some(PathBuf::from(value)),
+            "INFR" => self.include_from = Some(PathBuf::from(value)),
+            "FIFR" => self.files_from = Some(PathBuf::from(value)),
+            "EXCLUDE" => self.filters.push(Filter::Exclude(value.to_string())),
+            "INCLUDE" => self.filters.push(Filter::Include(value.to_string())),
+            "SCHEDULE" => self.schedule = Some(Schedule::parse(value)?),
+            "AFTER" => self.after.push(value.to_string()),
+            "REQUIRE_MOUNT" => self.require_mount = Some(PathBuf::from(value)),
+            "REQUIRE_MARKER" => self.require_marker = Some(PathBuf::from(value)),
+            "REQUIRE_UUID" => self.require_uuid = Some(value.to_string()),
+            "PRESERVE" => {
+                self.preservation = match value {
+                    "basic" => Preservation::Basic,
+                    "archive" => Preservation::Archive,
+                    "full" => Preservation::Full,
+                    _ => {
+                        return Err(format!(
+                            "Invalid PRESERVE value '{}'. Expected 'basic', 'archive' or 'full'.",
+                            value
+                        ));
+                    }
+                }
+            }
+            "MIN_FREE" => match parse_size(value) {
+                Some(size) => self.min_free = size,
+                None => {
+                    return Err(format!(
+                        "Invalid MIN_FREE value '{}'. Expected a size such as 500M or 20G.",
+                        value
+                    ));
+                }
+            },
+            "MAX_DELETE" => match value.parse() {
+                Ok(max) => self.max_delete = Some(max),
+                Err(_) => return Err(format!("Invalid MAX_DELETE value '{}'.", value)),
+            },
+            "DELETE_THRESHOLD" => match value.parse() {
+                Ok(threshold) if (0.0..=100.0).contains(&threshold) => {
+                    self.delete_threshold = threshold
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid DELETE_THRESHOLD value '{}'. Expected a percentage.",
+                        value
+                    ));
+                }
+            },
+            "RSYNC_ARGS" => {
+                check_rsync_arg(value)?;
+                self.rsync_args.push(value.to_string());
+            }
+            "HIDDEN" => self.include_hidden = Some(parse_hidden(value)?),
+            "BPATH" => {
+                if self.is_update {
+                    return Err(String::from(
+                        "Unexpected BPATH parameter in update task configuration.",
+                    ));
+                } else {
+                    self.backup_path = Some(PathBuf::from(value));
+                }
+            }
+            "ENCRYPT" => {
+                if self.is_update {
+                    return Err(String::from(
+                        "Unexpected ENCRYPT parameter in update task configuration.",
+                    ));
+                }
+                self.encryption = match value {
+                    "passphrase" => Some(Encryption::Passphrase),
+                    "keyfile" => Some(Encryption::KeyFile),
+                    _ => {
+                        return Err(format!(
+                            "Invalid ENCRYPT value '{}'. Expected 'passphrase' or 'keyfile'.",
+                            value
+                        ));
+                    }
+                }
+            }
+            "KEYFILE" => self.key_file = Some(PathBuf::from(value)),
+            "BWLIMIT" => self.bwlimit = Some(priority::parse_bwlimit(value)?),
+            "WORK_BWLIMIT" => self.work_bwlimit = Some(priority::parse_bwlimit(value)?),
+            "WORK_HOURS" => self.work_hours = Some(TimeWindow::parse(value)?),
+            "NICE" => self.nice = Some(priority::parse_nice(value)?),
+            "IONICE" => self.ionice = Some(value.parse()?),
+            "TIMEOUT" | "IO_TIMEOUT" | "RETRY_DELAY" => {
+                let duration = match parse_duration(value) {
+                    Some(duration) => duration,
+                    None => {
+                        return Err(format!(
+                            "Invalid {} value '{}'. Expected a duration such as 90s, 30m or 6h.",
+                            key, value
+                        ));
+                    }
+                };
+                match key {
+                    "TIMEOUT" => self.timeout = Some(duration),
+                    "IO_TIMEOUT" => self.io_timeout = Some(duration),
+                    _ => self.retry_delay = duration,
+                }
+            }
+            "RETRIES" => match value.parse() {
+                Ok(retries) => self.retries = retries,
+                Err(_) => return Err(format!("Invalid RETRIES value '{}'.", value)),
+            },
+            "CDST" => self.compare_dest.push(PathBuf::from(value)),
+            "LDST" => self.link_dest.push(PathBuf::from(value)),
+            "ID" => self.id = Some(value.to_string()),
+            _ => {
+                return Err(format!(
+                    "Unexpected line '{}={}' in configuration.",
+                    key, value
+                ));
+            }
+        };
+        Ok(())
+    }
+
+    fn set_flag(&mut self, flag: &str) -> Result<(), String> {
+        match flag {
+            "[EXCLUDE OTHERS]" => {
+                self.exclude_others = true;
+            }
+            "[CONFIRM]" => {
+                self.always_confirm = true;
+            }
+            "[MIRROR]" => {
+                if self.is_update {
+                    self.mirror = true;
+                } else {
+                    return Err(String::from(
+                        "Unexpected [MIRROR] tag in backup task configuration.",
+                    ));
+                }
+            }
+            "[COMPARE BPATH]" => {
+                if self.is_update {
+                    return Err(String::from(
+                        "Unexpected [COMPARE BPATH] tag in update task configuration.",
+                    ));
+                } else {
+                    self.compare_paths = true;
+                }
+            }
+            _ => {
+                return Err(format!("Unexpected line '{}' in configuration.", flag));
+            }
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{self, Entry, Format};
+
+    /// Parses a task from the lines between [UPDATE] or [BACKUP] and [END].
+    /// The paths don't need to exist.
+    fn task(is_update: bool, lines: &str) -> Task {
+        let kind = if is_update { "[UPDATE]" } else { "[BACKUP]" };
+        let contents = format!("{}\n{}\n[END]\n", kind, lines);
+        match format::parse(Format::Lines, &contents).unwrap().pop() {
+            Some(Entry::Task { is_update, items }) => {
+                Task::from_items(&items, is_update, true).unwrap()
+            }
+            _ => panic!("No task in {}", contents),
+        }
+    }
+
+    fn options() -> RunOptions {
+        RunOptions {
+            quiet: false,
+            debug: false,
+            dry_run: false,
+            include_hidden: false,
+            rsync_args: Vec::new(),
+            bwlimit: None,
+            nice: None,
+            ionice: None,
+            rsync: PathBuf::from("rsync"),
+        }
+    }
+
+    /// An itemized line with the paths it reports as missing, extra and
+    /// differing
+    type ItemizedCase<'a> = (&'a str, &'a [&'a str], &'a [&'a str], &'a [(&'a str, &'a [&'a str])]);
+
+    #[test]
+    fn sorts_itemized_changes() {
+        let cases: [ItemizedCase; 11] = [
+            (">f+++++++++ new.txt", &["new.txt"], &[], &[]),
+            ("cd+++++++++ new dir/", &["new dir/"], &[], &[]),
+            ("cL+++++++++ link -> target", &["link -> target"], &[], &[]),
+            ("*deleting   old.txt", &[], &["old.txt"], &[]),
+            ("*deleting   old dir/", &[], &["old dir/"], &[]),
+            ("cd..t...... dir/", &[], &[], &[]),
+            (".d..t...... dir/", &[], &[], &[]),
+            (".d...p..... dir/", &[], &[], &[("dir/", &["permissions"])]),
+            (">f.st...... file", &[], &[], &[("file", &["size", "modification time"])]),
+            (">fc........ file", &[], &[], &[("file", &["checksum"])]),
+            (".f....og..x file", &[], &[], &[("file", &["owner", "group", "extended attributes"])]),
+        ];
+        for (line, missing, extra, differing) in cases {
+            let mut verification = Verification {
+                missing: Vec::new(),
+                extra: Vec::new(),
+                differing: Vec::new(),
+            };
+            verification.add_itemized(line);
+            assert_eq!(verification.missing, missing, "{}", line);
+            assert_eq!(verification.extra, extra, "{}", line);
+            let expected: Vec<(String, Vec<&str>)> = differing
+                .iter()
+                .map(|(path, changes)| (path.to_string(), changes.to_vec()))
+                .collect();
+            assert_eq!(verification.differing, expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn ignores_other_output() {
+        let mut verification = Verification {
+            missing: Vec::new(),
+            extra: Vec::new(),
+            differing: Vec::new(),
+        };
+        let lines = [
+            "sending incremental file list",
+            "",
+            ".f          unchanged",
+            "total size is 0",
+        ];
+        for line in lines {
+            verification.add_itemized(line);
+        }
+        assert!(verification.is_clean());
+    }
+
+    #[test]
+    fn size_estimate_overrides_human_readable_sizes() {
+        let task = task(true, "SRC=/src\nDST=/dst\nRSYNC_ARGS=-h");
+        let extra = ["--stats", "--no-human-readable"];
+        let args = task.rsync_args(&options(), Path::new("/dst"), &extra).unwrap();
+        let position = |arg: &str| args.iter().rposition(|other| other == arg).unwrap();
+        assert!(position("--no-human-readable") > position("-h"));
+        assert_eq!(&args[args.len() - 2..], ["/src", "/dst"]);
+    }
+
+    #[test]
+    fn lists_regular_files_from_rsync_output() {
+        let lines = [
+            ("-rw-r--r--          1234 2024/01/15 10:00:00 src/a b.txt", Some("src/a b.txt")),
+            ("-rw-r--r--             0 2024/01/15 10:00:00 src/ lead", Some("src/ lead")),
+            ("drwxr-xr-x          4096 2024/01/15 10:00:00 src/dir", None),
+            ("lrwxrwxrwx             6 2024/01/15 10:00:00 src/link -> target", None),
+            ("", None),
+        ];
+        for (line, path) in lines {
+            assert_eq!(Task::listed_file(line), path, "{}", line);
+        }
+    }
+
+    #[test]
+    fn staging_directories_are_kept_per_task() {
+        let a = task(false, "ID=a\nSRC=/src/a\nDST=/dst\nENCRYPT=passphrase");
+        let b = task(false, "ID=b\nSRC=/src/b\nDST=/dst\nENCRYPT=passphrase");
+        let unnamed = task(false, "SRC=/src/c\nDST=/dst\nENCRYPT=passphrase");
+        assert_ne!(a.staging_dir(), b.staging_dir());
+        assert_ne!(a.staging_dir(), unnamed.staging_dir());
+        let args = a.rsync_args(&options(), &a.staging_dir(), &[]).unwrap();
+        assert!(args.iter().any(|arg| arg == "--delete-excluded"));
+    }
+
+    #[test]
+    fn unlock_rejects_a_different_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (key_file, other) = (dir.path().join("key"), dir.path().join("other"));
+        crypto::generate_key_file(&key_file).unwrap();
+        crypto::generate_key_file(&other).unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("file.txt"), "contents").unwrap();
+        let dst = dir.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        let output = dst.join(format!("2024-01-01--00_00{}", crypto::EXTENSION));
+        let key = Key::from_file(&key_file).unwrap();
+        archive::export_encrypted("2024-01-01--00_00", &[src], &output, &key).unwrap();
+
+        let lines = format!(
+            "SRC=/src\nDST={}\nENCRYPT=keyfile\nKEYFILE={}",
+            dst.display(),
+            other.display()
+        );
+        let err = task(false, &lines).unlock(false).unwrap_err();
+        assert!(err.starts_with("Key doesn't match the latest snapshot"), "{}", err);
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_an_hour() {
+        let task = task(false, "SRC=/src\nDST=/dst\nRETRIES=20\nRETRY_DELAY=1m");
+        let delays: Vec<u64> = [0, 1, 2, 5, 6, 7, 19]
+            .iter()
+            .map(|retries| task.delay_before_retry(*retries).as_secs())
+            .collect();
+        assert_eq!(delays, [60, 120, 240, 1920, 3600, 3600, 3600]);
+    }
+
+    #[test]
+    fn retries_only_transient_failures() {
+        use std::os::unix::process::ExitStatusExt;
+        let status = |code: i32| ExitStatus::from_raw(code << 8);
+        assert!(Task::check_status(status(0)).is_ok());
+        for code in [10, 12, 30, 35, 255] {
+            assert!(Task::check_status(status(code)).unwrap_err().transient, "{}", code);
+        }
+        for code in [1, 11, 23, 24] {
+            assert!(!Task::check_status(status(code)).unwrap_err().transient, "{}", code);
+        }
+        // Killed by a signal
+        assert!(!Task::check_status(ExitStatus::from_raw(libc::SIGKILL)).unwrap_err().transient);
+    }
+
+    #[test]
+    fn keeps_partial_files_when_runs_can_be_stopped() {
+        let has_partial = |lines: &str| {
+            let task = task(false, &format!("SRC=/src\nDST=/dst\n{}", lines));
+            let args = task.rsync_args(&options(), Path::new("/dst"), &[]).unwrap();
+            args.iter().any(|arg| arg == "--partial")
+        };
+        assert!(!has_partial(""));
+        assert!(has_partial("RETRIES=2"));
+        assert!(has_partial("TIMEOUT=2h"));
+    }
+
+    #[test]
+    fn converted_configurations_load_the_same_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (lines, toml) = (dir.path().join("backup.conf"), dir.path().join("backup.toml"));
+        let config = include_str!("../tests/fixtures/backup.conf");
+        let backup_path = format!("BPATH={}", dir.path().display());
+        fs::write(&lines, config.replace("BPATH=/mnt/old", &backup_path)).unwrap();
+        assert_eq!(crate::config::convert(&lines, &toml), Ok(0));
+        // Debug mode doesn't require the paths to exist
+        let original = crate::config::load_tasks(&lines, true).unwrap();
+        let converted = crate::config::load_tasks(&toml, true).unwrap();
+        assert_eq!(original.len(), 2);
+        assert_eq!(converted.len(), original.len());
+        let target = Path::new("/target");
+        for (original, converted) in original.iter().zip(&converted) {
+            assert_eq!(converted.get_id(), original.get_id());
+            assert_eq!(converted.is_update, original.is_update);
+            assert_eq!(converted.after, original.after);
+            assert_eq!(converted.include_hidden, original.include_hidden);
+            assert_eq!(
+                converted.schedule.as_ref().map(|schedule| schedule.to_systemd_timer()),
+                original.schedule.as_ref().map(|schedule| schedule.to_systemd_timer())
+            );
+            let args = |task: &Task| task.rsync_args(&options(), target, &[]).unwrap();
+            assert_eq!(args(converted), args(original), "{}", original.get_id());
+        }
+    }
+
+    #[test]
+    fn skips_tasks_whose_prerequisites_did_not_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("backup.conf");
+        let contents: String = [("a", ""), ("b", ""), ("c", "AFTER=a\nAFTER=b\n")]
+            .iter()
+            .map(|(id, after)| format!("[UPDATE]\nID={}\nSRC=/src\nDST=/dst\n{}[END]\n", id, after))
+            .collect();
+        fs::write(&config, contents).unwrap();
+        let tasks = crate::config::load_tasks(&config, true).unwrap();
+        let (a, b, c) = (&tasks[0], &tasks[1], &tasks[2]);
+
+        assert_eq!(blocking_prerequisite(c, &Vec::new()), None);
+        let mut outcomes = vec![(a, TaskOutcome::Completed(0))];
+        assert_eq!(blocking_prerequisite(c, &outcomes), None);
+        outcomes.push((b, TaskOutcome::Skipped(String::from("Locked"))));
+        let why = blocking_prerequisite(c, &outcomes);
+        assert_eq!(why.as_deref(), Some("Prerequisite b was skipped"));
+        outcomes[0].1 = TaskOutcome::Failed(String::from("Backup failed"));
+        let why = blocking_prerequisite(c, &outcomes);
+        assert_eq!(why.as_deref(), Some("Prerequisite a failed"));
+        // Tasks without prerequisites are never blocked
+        assert_eq!(blocking_prerequisite(b, &outcomes), None);
+    }
+
+    #[test]
+    fn destinations_need_their_marker_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().display();
+        let task = task(true, &format!("SRC=/src\nDST={}\nREQUIRE_MARKER=.backup-drive", dst));
+        let marker = dir.path().join(".backup-drive");
+        let err = format!("Marker file {} not found", marker.display());
+        assert_eq!(task.check_destination(), Err(err));
+        fs::write(&marker, "").unwrap();
+        assert_eq!(task.check_destination(), Ok(()));
+    }
+
+    #[test]
+    fn destinations_need_their_mount_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let requiring = |mount_point: &Path| {
+            let lines = format!(
+                "SRC=/src\nDST={}\nREQUIRE_MOUNT={}",
+                dir.path().display(),
+                mount_point.display()
+            );
+            task(true, &lines).check_destination()
+        };
+        // A directory on the same filesystem as its parent isn't a mount point
+        let err = format!("{} is not mounted", dir.path().display());
+        assert_eq!(requiring(dir.path()), Err(err));
+        let missing = dir.path().join("missing");
+        assert_eq!(requiring(&missing), Err(format!("{} is not mounted", missing.display())));
+        assert_eq!(requiring(Path::new("/")), Ok(()));
+    }
 }
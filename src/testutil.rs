@@ -0,0 +1,55 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Helpers for tests, shared by the unit tests and, through tests/common, the
+// integration tests
+
+use std::fs;
+use std::path::Path;
+
+/// Writes a file below a directory, creating its parent directories
+pub fn write(root: &Path, path: &str, contents: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+/// Writes two layers of a snapshot of a [COMPARE BPATH] task. The newer one
+/// changes a file, replaces a directory with a file and a file with a
+/// directory, and leaves another file to the older one.
+pub fn write_layers(new: &Path, old: &Path) {
+    write(old, "same.txt", "same");
+    write(old, "changed.txt", "old");
+    write(old, "became_file/child.txt", "old");
+    write(old, "became_dir", "old");
+    write(new, "changed.txt", "new");
+    write(new, "became_file", "new");
+    write(new, "became_dir/child.txt", "new");
+}
+
+/// Checks that a directory holds the merge of the layers written by
+/// `write_layers`
+pub fn assert_merged(root: &Path) {
+    let read = |path: &str| fs::read_to_string(root.join(path)).unwrap();
+    assert_eq!(read("same.txt"), "same");
+    assert_eq!(read("changed.txt"), "new");
+    assert_eq!(read("became_file"), "new");
+    assert_eq!(read("became_dir/child.txt"), "new");
+    let mut names: Vec<String> = fs::read_dir(root)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["became_dir", "became_file", "changed.txt", "same.txt"]);
+}
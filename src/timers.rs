@@ -0,0 +1,503 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Generation of systemd timer units and crontab entries for scheduled tasks,
+// as an alternative to running `rsbackup daemon`. Each job runs
+// `rsbackup --safe run --only <id>` with the configuration file that was used
+// to generate it.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::schedule::Schedule;
+
+const UNIT_PREFIX: &str = "rsbackup-";
+const CRON_BEGIN: &str = "# BEGIN rsbackup scheduled tasks";
+const CRON_END: &str = "# END rsbackup scheduled tasks";
+const CRON_FILE: &str = "rsbackup.cron";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    Systemd,
+    Cron,
+}
+
+/// A scheduled task for which a job is generated
+pub struct Job<'a> {
+    pub id: &'a str,
+    pub schedule: &'a Schedule,
+}
+
+/// Where the generated files go. If a directory is given, files are only
+/// written there and nothing is installed on the system.
+pub struct Target {
+    pub backend: Backend,
+    pub dir: Option<PathBuf>,
+}
+
+/// Replaces characters that aren't allowed in systemd unit names
+fn unit_name(id: &str) -> String {
+    let name: String = id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("{}{}", UNIT_PREFIX, name)
+}
+
+fn systemd_quote(arg: &str) -> String {
+    if arg.contains(char::is_whitespace) || arg.contains('"') || arg.contains('\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.to_string()
+    }
+}
+
+fn shell_quote(arg: &str) -> String {
+    // cron treats unescaped % as a newline
+    format!("'{}'", arg.replace('\'', "'\\''")).replace('%', "\\%")
+}
+
+fn job_command(exe: &Path, config: &Path, id: &str) -> Vec<String> {
+    vec![
+        exe.display().to_string(),
+        String::from("-f"),
+        config.display().to_string(),
+        String::from("--safe"),
+        String::from("run"),
+        String::from("--only"),
+        id.to_string(),
+    ]
+}
+
+fn systemd_dir(target: &Target) -> Result<PathBuf, String> {
+    if let Some(dir) = &target.dir {
+        return Ok(dir.clone());
+    }
+    let config_home = match env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path,
+        _ => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => return Err(String::from("Could not determine home directory.")),
+        },
+    };
+    Ok(config_home.join("systemd").join("user"))
+}
+
+fn systemctl(args: &[&str]) -> Result<(), String> {
+    match Command::new("systemctl").arg("--user").args(args).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => Err(format!("systemctl --user {} failed", args.join(" "))),
+        Err(why) => Err(format!("Failed to run systemctl: {}", why)),
+    }
+}
+
+/// Lists the timer units previously installed by rsbackup
+fn installed_timers(dir: &Path) -> Vec<String> {
+    let mut timers: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| name.starts_with(UNIT_PREFIX) && name.ends_with(".timer"))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    timers.sort();
+    timers
+}
+
+fn read_crontab(target: &Target) -> Result<String, String> {
+    if let Some(dir) = &target.dir {
+        return Ok(fs::read_to_string(dir.join(CRON_FILE)).unwrap_or_default());
+    }
+    match Command::new("crontab").arg("-l").output() {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        }
+        // crontab -l fails if the user has no crontab yet
+        Ok(_) => Ok(String::new()),
+        Err(why) => Err(format!("Failed to run crontab: {}", why)),
+    }
+}
+
+fn write_crontab(target: &Target, contents: &str) -> Result<(), String> {
+    if let Some(dir) = &target.dir {
+        let path = dir.join(CRON_FILE);
+        return fs::write(&path, contents)
+            .map_err(|why| format!("Failed to write {}: {}", path.display(), why));
+    }
+    let mut child = match Command::new("crontab").arg("-").stdin(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(why) => return Err(format!("Failed to run crontab: {}", why)),
+    };
+    if let Some(stdin) = child.stdin.as_mut() {
+        if let Err(why) = stdin.write_all(contents.as_bytes()) {
+            return Err(format!("Failed to write crontab: {}", why));
+        }
+    }
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err(String::from("Failed to install crontab")),
+    }
+}
+
+/// Splits a crontab into the lines outside and inside the rsbackup block
+fn split_crontab(crontab: &str) -> (Vec<&str>, Vec<&str>) {
+    let mut outside = Vec::new();
+    let mut inside = Vec::new();
+    let mut in_block = false;
+    for line in crontab.lines() {
+        match line {
+            CRON_BEGIN => in_block = true,
+            CRON_END => in_block = false,
+            _ if in_block => inside.push(line),
+            _ => outside.push(line),
+        }
+    }
+    (outside, inside)
+}
+
+/// Generates and installs a job for each scheduled task, replacing any jobs
+/// installed previously. Returns the paths of the files that were written.
+pub fn install(target: &Target, jobs: &[Job], config: &Path) -> Result<Vec<PathBuf>, String> {
+    let exe = env::current_exe().map_err(|why| format!("Failed to locate rsbackup: {}", why))?;
+    let config = fs::canonicalize(config)
+        .map_err(|why| format!("Failed to locate {}: {}", config.display(), why))?;
+    let mut names = HashSet::new();
+    for job in jobs {
+        if !names.insert(unit_name(job.id)) {
+            return Err(format!(
+                "Task ID '{}' conflicts with another task after replacing special characters.",
+                job.id
+            ));
+        }
+    }
+    uninstall(target)?;
+    match target.backend {
+        Backend::Systemd => {
+            let dir = systemd_dir(target)?;
+            fs::create_dir_all(&dir)
+                .map_err(|why| format!("Failed to create {}: {}", dir.display(), why))?;
+            let mut written = Vec::new();
+            for job in jobs {
+                let name = unit_name(job.id);
+                let command: Vec<String> = job_command(&exe, &config, job.id)
+                    .iter()
+                    .map(|arg| systemd_quote(arg))
+                    .collect();
+                let service = format!(
+                    "[Unit]\nDescription=rsbackup task {id}\n\n[Service]\nType=oneshot\nExecStart={}\n",
+                    command.join(" "),
+                    id = job.id
+                );
+                let timer = format!(
+                    "[Unit]\nDescription=Run rsbackup task {id} on schedule\n\n[Timer]\n{}\n\n[Install]\nWantedBy=timers.target\n",
+                    job.schedule.to_systemd_timer().join("\n"),
+                    id = job.id
+                );
+                for (extension, contents) in [("service", service), ("timer", timer)] {
+                    let path = dir.join(format!("{}.{}", name, extension));
+                    fs::write(&path, contents)
+                        .map_err(|why| format!("Failed to write {}: {}", path.display(), why))?;
+                    written.push(path);
+                }
+            }
+            if target.dir.is_none() {
+                systemctl(&["daemon-reload"])?;
+                for job in jobs {
+                    systemctl(&["enable", "--now", &format!("{}.timer", unit_name(job.id))])?;
+                }
+            }
+            Ok(written)
+        }
+        Backend::Cron => {
+            let crontab = read_crontab(target)?;
+            let (mut lines, _) = split_crontab(&crontab);
+            let mut entries = Vec::new();
+            for job in jobs {
+                let time = job
+                    .schedule
+                    .to_cron()
+                    .map_err(|err| format!("Task {}: {}", job.id, err))?;
+                let command: Vec<String> = job_command(&exe, &config, job.id)
+                    .iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect();
+                entries.push(format!("{} {}", time, command.join(" ")));
+            }
+            lines.push(CRON_BEGIN);
+            lines.extend(entries.iter().map(|entry| entry.as_str()));
+            lines.push(CRON_END);
+            write_crontab(target, &(lines.join("\n") + "\n"))?;
+            Ok(match &target.dir {
+                Some(dir) => vec![dir.join(CRON_FILE)],
+                None => Vec::new(),
+            })
+        }
+    }
+}
+
+/// Removes all jobs previously installed by rsbackup
+pub fn uninstall(target: &Target) -> Result<(), String> {
+    match target.backend {
+        Backend::Systemd => {
+            let dir = systemd_dir(target)?;
+            let timers = installed_timers(&dir);
+            if timers.is_empty() {
+                return Ok(());
+            }
+            if target.dir.is_none() {
+                for timer in &timers {
+                    systemctl(&["disable", "--now", timer])?;
+                }
+            }
+            for timer in &timers {
+                let service = timer.replace(".timer", ".service");
+                for name in [timer, &service] {
+                    let path = dir.join(name);
+                    if path.exists() {
+                        fs::remove_file(&path)
+                            .map_err(|why| format!("Failed to remove {}: {}", path.display(), why))?;
+                    }
+                }
+            }
+            if target.dir.is_none() {
+                systemctl(&["daemon-reload"])?;
+            }
+            Ok(())
+        }
+        Backend::Cron => {
+            let crontab = read_crontab(target)?;
+            let (lines, inside) = split_crontab(&crontab);
+            if inside.is_empty() && !crontab.contains(CRON_BEGIN) {
+                return Ok(());
+            }
+            let mut contents = lines.join("\n");
+            if !contents.is_empty() {
+                contents.push('\n');
+            }
+            write_crontab(target, &contents)
+        }
+    }
+}
+
+/// Lists the installed jobs along with scheduled tasks that have no job and
+/// jobs for tasks that are no longer scheduled
+pub fn status(target: &Target, jobs: &[Job]) -> Result<(), String> {
+    let installed: Vec<String> = match target.backend {
+        Backend::Systemd => installed_timers(&systemd_dir(target)?)
+            .into_iter()
+            .map(|timer| timer.trim_end_matches(".timer").to_string())
+            .collect(),
+        Backend::Cron => {
+            let crontab = read_crontab(target)?;
+            let (_, inside) = split_crontab(&crontab);
+            for line in &inside {
+                println!("{}", line);
+            }
+            inside
+                .iter()
+                .filter_map(|line| line.split(" '--only' ").nth(1))
+                .map(|id| unit_name(id.trim_matches('\'')))
+                .collect()
+        }
+    };
+    for job in jobs {
+        let name = unit_name(job.id);
+        if installed.contains(&name) {
+            println!("{}: installed", job.id);
+        } else {
+            println!("{}: not installed", job.id);
+        }
+    }
+    for name in &installed {
+        if !jobs.iter().any(|job| &unit_name(job.id) == name) {
+            println!("{}: installed but no longer scheduled", name);
+        }
+    }
+    if target.backend == Backend::Systemd && target.dir.is_none() && !installed.is_empty() {
+        let pattern = format!("{}*", UNIT_PREFIX);
+        systemctl(&["list-timers", &pattern])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+        config: PathBuf,
+        daily: Schedule,
+        every_six_hours: Schedule,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let config = dir.path().join("backup.conf");
+            fs::write(&config, "").unwrap();
+            Fixture {
+                config: fs::canonicalize(config).unwrap(),
+                dir,
+                daily: Schedule::parse("30 2 * * *").unwrap(),
+                every_six_hours: Schedule::parse("every 6h").unwrap(),
+            }
+        }
+
+        fn target(&self, backend: Backend) -> Target {
+            Target {
+                backend,
+                dir: Some(self.dir.path().to_path_buf()),
+            }
+        }
+
+        fn read(&self, name: &str) -> String {
+            fs::read_to_string(self.dir.path().join(name)).unwrap()
+        }
+
+        fn exists(&self, name: &str) -> bool {
+            self.dir.path().join(name).exists()
+        }
+    }
+
+    fn exe() -> String {
+        env::current_exe().unwrap().display().to_string()
+    }
+
+    #[test]
+    fn writes_systemd_units() {
+        let fixture = Fixture::new();
+        let jobs = [
+            Job { id: "home", schedule: &fixture.daily },
+            Job { id: "my docs", schedule: &fixture.every_six_hours },
+        ];
+        let target = fixture.target(Backend::Systemd);
+        let written = install(&target, &jobs, &fixture.config).unwrap();
+        assert_eq!(written.len(), 4);
+
+        let service = fixture.read("rsbackup-home.service");
+        let command = format!(
+            "ExecStart={} -f {} --safe run --only home\n",
+            exe(),
+            fixture.config.display()
+        );
+        assert!(service.contains("Description=rsbackup task home\n"));
+        assert!(service.contains("Type=oneshot\n"));
+        assert!(service.contains(&command), "{}", service);
+        let timer = fixture.read("rsbackup-home.timer");
+        let calendar = "[Timer]\nOnCalendar=*-*-* 2:30:00\nPersistent=true\n";
+        assert!(timer.contains(calendar), "{}", timer);
+        assert!(timer.contains("[Install]\nWantedBy=timers.target\n"));
+
+        // IDs are quoted in commands and replaced in unit names
+        let service = fixture.read("rsbackup-my_docs.service");
+        assert!(service.contains("--only \"my docs\"\n"), "{}", service);
+        let timer = fixture.read("rsbackup-my_docs.timer");
+        let interval = "[Timer]\nOnBootSec=5min\nOnUnitActiveSec=21600s\n";
+        assert!(timer.contains(interval), "{}", timer);
+    }
+
+    #[test]
+    fn replaces_systemd_units() {
+        let fixture = Fixture::new();
+        let target = fixture.target(Backend::Systemd);
+        let jobs = [
+            Job { id: "home", schedule: &fixture.daily },
+            Job { id: "docs", schedule: &fixture.every_six_hours },
+        ];
+        install(&target, &jobs, &fixture.config).unwrap();
+        install(&target, &jobs[1..], &fixture.config).unwrap();
+        assert!(!fixture.exists("rsbackup-home.service"));
+        assert!(!fixture.exists("rsbackup-home.timer"));
+        assert!(fixture.exists("rsbackup-docs.timer"));
+
+        uninstall(&target).unwrap();
+        assert!(!fixture.exists("rsbackup-docs.service"));
+        assert!(!fixture.exists("rsbackup-docs.timer"));
+        assert!(fixture.exists("backup.conf"));
+    }
+
+    #[test]
+    fn rejects_conflicting_unit_names() {
+        let fixture = Fixture::new();
+        let jobs = [
+            Job { id: "a b", schedule: &fixture.daily },
+            Job { id: "a/b", schedule: &fixture.daily },
+        ];
+        let target = fixture.target(Backend::Systemd);
+        assert!(install(&target, &jobs, &fixture.config).is_err());
+    }
+
+    #[test]
+    fn writes_crontab_block() {
+        let fixture = Fixture::new();
+        let target = fixture.target(Backend::Cron);
+        let existing = "MAILTO=admin\n0 3 * * * other-job\n";
+        fs::write(fixture.dir.path().join(CRON_FILE), existing).unwrap();
+        let jobs = [
+            Job { id: "home", schedule: &fixture.daily },
+            Job { id: "docs", schedule: &fixture.every_six_hours },
+        ];
+        install(&target, &jobs, &fixture.config).unwrap();
+        let command = |id: &str| {
+            format!(
+                "'{}' '-f' '{}' '--safe' 'run' '--only' '{}'",
+                exe(),
+                fixture.config.display(),
+                id
+            )
+        };
+        let expected = format!(
+            "{}{}\n30 2 * * * {}\n0 */6 * * * {}\n{}\n",
+            existing,
+            CRON_BEGIN,
+            command("home"),
+            command("docs"),
+            CRON_END
+        );
+        assert_eq!(fixture.read(CRON_FILE), expected);
+
+        // Installing again replaces the block instead of adding another one
+        install(&target, &jobs[..1], &fixture.config).unwrap();
+        let crontab = fixture.read(CRON_FILE);
+        assert_eq!(crontab.matches(CRON_BEGIN).count(), 1);
+        assert!(!crontab.contains("'docs'"));
+
+        uninstall(&target).unwrap();
+        assert_eq!(fixture.read(CRON_FILE), existing);
+    }
+
+    #[test]
+    fn rejects_intervals_cron_cannot_express() {
+        let fixture = Fixture::new();
+        let schedule = Schedule::parse("every 7h").unwrap();
+        let jobs = [Job { id: "home", schedule: &schedule }];
+        assert!(install(&fixture.target(Backend::Cron), &jobs, &fixture.config).is_err());
+    }
+
+    #[test]
+    fn quotes_shell_arguments() {
+        assert_eq!(shell_quote("it's 100%"), "'it'\\''s 100\\%'");
+        assert_eq!(systemd_quote("a \"b\""), "\"a \\\"b\\\"\"");
+        assert_eq!(systemd_quote("plain"), "plain");
+    }
+}
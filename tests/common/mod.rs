@@ -0,0 +1,51 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+#[path = "../../src/testutil.rs"]
+mod testutil;
+
+#[allow(unused_imports)]
+pub use testutil::*;
+
+/// Writes a shell script named rsync into the given directory
+pub fn fake_rsync(dir: &Path, script: &str) {
+    let path = dir.join("rsync");
+    fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Runs rsbackup with the given configuration file. With `bin`, rsync is
+/// looked up there before the rest of PATH.
+pub fn rsbackup(config: &Path, bin: Option<&Path>) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rsbackup"));
+    command.arg("-f").arg(config);
+    if let Some(bin) = bin {
+        let mut path = OsString::from(bin);
+        path.push(":");
+        path.push(env::var_os("PATH").unwrap_or_default());
+        command.env("PATH", path);
+    }
+    command
+}
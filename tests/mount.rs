@@ -0,0 +1,103 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Mounts the snapshots of a [COMPARE BPATH] task with the mount command and
+// checks that they are shown merged. Needs FUSE and fusermount.
+
+#![cfg(feature = "fuse")]
+
+mod common;
+
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const OLD: &str = "2024-01-01--00_00";
+const NEW: &str = "2024-01-02--00_00";
+
+/// Unmounts the filesystem and waits for rsbackup to exit, also when a check
+/// fails
+struct Mount {
+    child: Child,
+    mountpoint: PathBuf,
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let _ = Command::new("fusermount").arg("-u").arg(&self.mountpoint).status();
+        if !wait_for(|| matches!(self.child.try_wait(), Ok(Some(_)))) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Waits up to ten seconds for a condition to hold. Returns whether it did.
+fn wait_for(mut condition: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while !condition() {
+        if start.elapsed() > Duration::from_secs(10) {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    true
+}
+
+fn mkfifo(path: &Path) {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o644) }, 0);
+}
+
+#[test]
+fn mounts_merged_snapshots() {
+    let dir = tempfile::tempdir().unwrap();
+    let dst = dir.path().join("dst");
+    let (old, new) = (dst.join(OLD), dst.join(NEW));
+    common::write_layers(&new.join("src"), &old.join("src"));
+    let config = dir.path().join("backup.conf");
+    let contents = format!(
+        "[BACKUP]\nID=docs\nSRC=/src\nDST={}\nBPATH={}\n[COMPARE BPATH]\n[END]\n",
+        dst.display(),
+        dst.display()
+    );
+    fs::write(&config, contents).unwrap();
+    mkfifo(&new.join("pipe"));
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir(&mountpoint).unwrap();
+
+    let child = common::rsbackup(&config, None)
+        .args(["mount", "docs"])
+        .arg(&mountpoint)
+        .spawn()
+        .unwrap();
+    let mount = Mount {
+        child,
+        mountpoint: mountpoint.clone(),
+    };
+    assert!(wait_for(|| mountpoint.join(NEW).exists()), "The snapshots were not mounted");
+
+    let snapshot = mountpoint.join(NEW).join("src");
+    common::assert_merged(&snapshot);
+    assert!(!snapshot.join("became_file/child.txt").exists());
+    let pipe = fs::symlink_metadata(mountpoint.join(NEW).join("pipe")).unwrap();
+    assert!(pipe.file_type().is_fifo());
+    assert!(!mountpoint.join(OLD).join("src/became_dir/child.txt").exists());
+    drop(mount);
+}
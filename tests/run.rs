@@ -0,0 +1,64 @@
+// Copyright (C) 2022 Arc676/Alessandro Vinciguerra <alesvinciguerra@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation (version 3).
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Runs tasks with the run command, using a script in place of rsync
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+/// Writes a configuration with an update task `a` and returns its path
+fn config(dir: &Path) -> PathBuf {
+    let (src, dst) = (dir.join("src"), dir.join("dst"));
+    fs::create_dir_all(&src).unwrap();
+    fs::create_dir_all(&dst).unwrap();
+    let config = dir.join("backup.conf");
+    let contents = format!(
+        "[UPDATE]\nID=a\nSRC={}/\nDST={}\n[END]\n",
+        src.display(),
+        dst.display()
+    );
+    fs::write(&config, contents).unwrap();
+    config
+}
+
+fn run(script: &str) -> Output {
+    let dir = tempfile::tempdir().unwrap();
+    let config = config(dir.path());
+    common::fake_rsync(dir.path(), script);
+    common::rsbackup(&config, Some(dir.path()))
+        .args(["--safe", "--no-wait", "run", "--only", "a", "--no-space-check"])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn succeeds_when_all_tasks_complete() {
+    let output = run("exit 0");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 completed, 0 skipped, 0 failed"), "{}", stdout);
+    assert!(stdout.contains("Backup complete."), "{}", stdout);
+}
+
+#[test]
+fn fails_when_a_task_fails() {
+    let output = run("exit 23");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(stdout.contains("0 completed, 0 skipped, 1 failed"), "{}", stdout);
+    assert!(!stdout.contains("Backup complete."), "{}", stdout);
+}